@@ -0,0 +1,308 @@
+//! Alarms of players, stored in their configuration.
+//!
+//! The API encodes every alarm as a string of comma-separated fields, e.g.
+//! `"1111100,0700,4OD25,,,1"`: the days it rings, from Monday, its time, the
+//! sound or card to play, two fields this library keeps as they are, and
+//! whether it is enabled.
+
+use chrono::{NaiveTime, Weekday};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::api::Client;
+use crate::error::{Error, Result};
+use crate::model::DeviceConfig;
+
+/// Number of fields of an encoded alarm.
+const FIELDS: usize = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alarm {
+    /// Days the alarm rings, from Monday. An alarm ringing on no day rings
+    /// once.
+    pub days: [bool; 7],
+    pub time: NaiveTime,
+    /// ID of the alarm sound or of the card to play.
+    pub sound: String,
+    pub enabled: bool,
+    unknown: [String; 2],
+}
+
+impl Alarm {
+    /// Creates an enabled alarm ringing once.
+    pub fn new(time: NaiveTime, sound: &str) -> Alarm {
+        Alarm {
+            days: [false; 7],
+            time,
+            sound: sound.to_string(),
+            enabled: true,
+            unknown: Default::default(),
+        }
+    }
+
+    pub fn day(mut self, day: Weekday) -> Self {
+        self.days[day.num_days_from_monday() as usize] = true;
+        self
+    }
+
+    pub fn rings_on(&self, day: Weekday) -> bool {
+        self.days[day.num_days_from_monday() as usize]
+    }
+
+    /// Checks that the alarm can be encoded.
+    pub fn validate(&self) -> Result<()> {
+        if self.sound.is_empty() {
+            return Err(Error::InvalidAlarm("no sound or card to play".to_string()));
+        }
+        if self.sound.contains(',') {
+            return Err(Error::InvalidAlarm(format!(
+                "invalid sound \"{}\"",
+                self.sound
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Alarm {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Alarm> {
+        let invalid = || Error::InvalidAlarm(format!("\"{}\"", value));
+        let fields: Vec<&str> = value.split(',').collect();
+        if fields.len() != FIELDS || fields[0].len() != 7 {
+            return Err(invalid());
+        }
+
+        let mut days = [false; 7];
+        for (day, flag) in days.iter_mut().zip(fields[0].chars()) {
+            *day = match flag {
+                '0' => false,
+                '1' => true,
+                _ => return Err(invalid()),
+            };
+        }
+        let time = NaiveTime::parse_from_str(fields[1], "%H%M").map_err(|_| invalid())?;
+        Ok(Alarm {
+            days,
+            time,
+            sound: fields[2].to_string(),
+            enabled: fields[5] == "1",
+            unknown: [fields[3].to_string(), fields[4].to_string()],
+        })
+    }
+}
+
+impl fmt::Display for Alarm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days: String = self
+            .days
+            .iter()
+            .map(|day| if *day { '1' } else { '0' })
+            .collect();
+        write!(
+            f,
+            "{},{},{},{},{},{}",
+            days,
+            self.time.format("%H%M"),
+            self.sound,
+            self.unknown[0],
+            self.unknown[1],
+            u8::from(self.enabled)
+        )
+    }
+}
+
+/// Decodes the alarms of a configuration, skipping the entries that cannot be
+/// decoded.
+pub fn alarms(config: &DeviceConfig) -> Vec<Alarm> {
+    config
+        .alarms
+        .iter()
+        .flatten()
+        .filter_map(|alarm| alarm.parse().ok())
+        .collect()
+}
+
+/// Entries of a configuration that cannot be decoded as alarms, e.g. in a
+/// format of a newer firmware.
+pub fn unknown_alarms(config: &DeviceConfig) -> Vec<String> {
+    config
+        .alarms
+        .iter()
+        .flatten()
+        .filter(|alarm| alarm.parse::<Alarm>().is_err())
+        .cloned()
+        .collect()
+}
+
+/// Validates and encodes alarms into a configuration. Entries that cannot be
+/// decoded are kept as they are, after the alarms.
+pub fn set_alarms(config: &mut DeviceConfig, alarms: &[Alarm]) -> Result<()> {
+    for alarm in alarms {
+        alarm.validate()?;
+    }
+    let unknown = unknown_alarms(config);
+    config.alarms = Some(alarms.iter().map(Alarm::to_string).chain(unknown).collect());
+    Ok(())
+}
+
+fn no_alarm(index: usize) -> Error {
+    Error::InvalidAlarm(format!("no alarm {}", index + 1))
+}
+
+/// Applies a change to the alarms of a device, keeping its other settings.
+fn change_alarms<F>(client: &Client, device_id: &str, change: F) -> Result<Vec<Alarm>>
+where
+    F: FnOnce(&mut Vec<Alarm>) -> Result<()>,
+{
+    let mut config = client.get_device_config(device_id)?;
+    let mut list = alarms(&config);
+    change(&mut list)?;
+    set_alarms(&mut config, &list)?;
+    client.update_device_config(device_id, &config)?;
+    Ok(list)
+}
+
+pub fn list_alarms(client: &Client, device_id: &str) -> Result<Vec<Alarm>> {
+    Ok(alarms(&client.get_device_config(device_id)?))
+}
+
+/// Adds an alarm, returning the alarms of the device.
+pub fn add_alarm(client: &Client, device_id: &str, alarm: Alarm) -> Result<Vec<Alarm>> {
+    change_alarms(client, device_id, |list| {
+        list.push(alarm);
+        Ok(())
+    })
+}
+
+/// Replaces the alarm at `index`, from 0, returning the alarms of the device.
+pub fn update_alarm(
+    client: &Client,
+    device_id: &str,
+    index: usize,
+    alarm: Alarm,
+) -> Result<Vec<Alarm>> {
+    edit_alarm(client, device_id, index, |current| *current = alarm)
+}
+
+/// Changes the alarm at `index`, from 0, with `edit`, returning the alarms of
+/// the device. The alarm is read and written back in a single update of the
+/// configuration, and validated before it.
+pub fn edit_alarm<F>(client: &Client, device_id: &str, index: usize, edit: F) -> Result<Vec<Alarm>>
+where
+    F: FnOnce(&mut Alarm),
+{
+    change_alarms(client, device_id, |list| {
+        edit(list.get_mut(index).ok_or_else(|| no_alarm(index))?);
+        Ok(())
+    })
+}
+
+/// Removes the alarm at `index`, from 0, returning the alarms of the device.
+pub fn remove_alarm(client: &Client, device_id: &str, index: usize) -> Result<Vec<Alarm>> {
+    change_alarms(client, device_id, |list| {
+        if index >= list.len() {
+            return Err(no_alarm(index));
+        }
+        list.remove(index);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client, serve};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn encode_alarms() {
+        let alarm: Alarm = "1111100,0715,4OD25,,8,1".parse().unwrap();
+        assert_eq!(alarm.time, NaiveTime::from_hms_opt(7, 15, 0).unwrap());
+        assert!(alarm.rings_on(Weekday::Fri));
+        assert!(!alarm.rings_on(Weekday::Sat));
+        assert_eq!(alarm.sound, "4OD25");
+        assert!(alarm.enabled);
+        assert_eq!(alarm.to_string(), "1111100,0715,4OD25,,8,1");
+
+        let alarm = Alarm::new(NaiveTime::from_hms_opt(9, 0, 0).unwrap(), "5WsQ9")
+            .day(Weekday::Sat)
+            .day(Weekday::Sun);
+        assert_eq!(alarm.to_string(), "0000011,0900,5WsQ9,,,1");
+
+        assert!("1111100,7:15,4OD25,,,1".parse::<Alarm>().is_err());
+        assert!("111110,0715,4OD25,,,1".parse::<Alarm>().is_err());
+        assert!("1111100,0715,4OD25".parse::<Alarm>().is_err());
+    }
+
+    #[test]
+    fn validate_before_encoding() {
+        let mut config = DeviceConfig::default();
+        let time = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
+        assert!(set_alarms(&mut config, &[Alarm::new(time, "")]).is_err());
+        assert!(set_alarms(&mut config, &[Alarm::new(time, "a,b")]).is_err());
+        assert!(config.alarms.is_none());
+
+        set_alarms(&mut config, &[Alarm::new(time, "4OD25")]).unwrap();
+        assert_eq!(
+            config.alarms,
+            Some(vec!["0000000,0700,4OD25,,,1".to_string()])
+        );
+        assert_eq!(alarms(&config).len(), 1);
+    }
+
+    #[test]
+    fn keep_unknown_alarms() {
+        let mut config = DeviceConfig {
+            alarms: Some(vec![
+                "1111100,0700,4OD25,,,1".to_string(),
+                "1111100,0700".to_string(),
+            ]),
+            ..DeviceConfig::default()
+        };
+        let mut list = alarms(&config);
+        assert_eq!(list.len(), 1);
+        assert_eq!(unknown_alarms(&config), vec!["1111100,0700"]);
+
+        list[0].enabled = false;
+        set_alarms(&mut config, &list).unwrap();
+        assert_eq!(
+            config.alarms,
+            Some(vec![
+                "1111100,0700,4OD25,,,0".to_string(),
+                "1111100,0700".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn edit_in_one_update() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = {
+            let requests = requests.clone();
+            serve(move |method, _, body| {
+                let body: Value = serde_json::from_slice(body).unwrap_or_default();
+                requests.lock().unwrap().push((method.to_string(), body));
+                let config =
+                    json!({ "alarms": ["1111100,0700,4OD25,,,1", "0000011,0900,5WsQ9,,,1"] });
+                json!({ "device": { "config": config } })
+                    .to_string()
+                    .into_bytes()
+            })
+        };
+        let client = client(&url);
+        let alarms = edit_alarm(&client, "d", 1, |alarm| alarm.enabled = false).unwrap();
+        assert!(!alarms[1].enabled);
+
+        let requests = requests.lock().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(methods, vec!["GET", "POST"]);
+        assert_eq!(
+            requests[1].1["config"]["alarms"],
+            json!(["1111100,0700,4OD25,,,1", "0000011,0900,5WsQ9,,,0"])
+        );
+        assert!(edit_alarm(&client, "d", 2, |_| ()).is_err());
+    }
+}
@@ -1,28 +1,65 @@
+//! Blocking client for the Yoto REST API and its OAuth server.
+
 use chrono::{DateTime, TimeDelta, Utc};
-use reqwest::{header, header::HeaderMap, StatusCode};
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    header,
+    header::HeaderMap,
+    StatusCode,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::default::Default;
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::sleep;
 use std::time::Duration;
 
+use crate::error::{Error, Result};
 use crate::model::*;
+use crate::store::TokenStore;
+
+/// Callback invoked with the new token whenever the client refreshes it,
+/// along with the error that prevented saving it to the token store, if any.
+pub type TokenCallback = Box<dyn Fn(&Token, Option<&Error>) + Send + Sync>;
 
-#[derive(Default)]
 pub struct Client {
     pub id: String,
-    pub token: Option<Token>,
+    token: Mutex<Option<Token>>,
+    store: Option<Arc<dyn TokenStore>>,
+    on_token_refresh: Option<TokenCallback>,
+    config: Config,
     client: reqwest::blocking::Client,
 }
 
-pub enum ClientError {
-    NotFound,
-    Failed,
+/// Endpoints and HTTP settings used by a [`Client`].
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Root of the REST API, e.g. `https://api.yotoplay.com`.
+    pub base_url: String,
+    /// Device code endpoint of the OAuth server.
+    pub auth_url: String,
+    /// Token endpoint of the OAuth server.
+    pub token_url: String,
+    /// OAuth audience requested for new tokens.
+    pub audience: String,
+    /// Total timeout of a single HTTP request.
+    pub timeout: Option<Duration>,
+    /// Timeout for establishing the connection.
+    pub connect_timeout: Option<Duration>,
+    pub user_agent: String,
+}
+
+pub struct ClientBuilder {
+    pub(crate) id: String,
+    pub(crate) token: Option<Token>,
+    pub(crate) store: Option<Arc<dyn TokenStore>>,
+    pub(crate) on_token_refresh: Option<TokenCallback>,
+    pub(crate) config: Config,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Token {
     access_token: String,
@@ -34,19 +71,55 @@ pub struct Token {
     valid_until: DateTime<Utc>,
 }
 
+/// Device code login started with [`Client::start_login`].
+///
+/// The user must visit the verification URI and enter the user code before
+/// the login expires, while the login is polled with [`Client::poll_login`]
+/// or [`Client::wait_login`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceLogin {
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_at: DateTime<Utc>,
+    /// Minimum delay between two polls, as requested by the server.
+    pub interval: Duration,
+    device_code: String,
+}
+
+/// Outcome of a single poll of a [`DeviceLogin`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginStatus {
+    /// The user has not confirmed the login yet.
+    Pending,
+    /// Polling too fast; the login interval has been increased.
+    SlowDown,
+    /// The login is confirmed and the client now holds a token.
+    Complete,
+}
+
 pub enum RefreshStatus {
     AlreadyValid,
     Refreshed,
+    #[deprecated(note = "failed refreshes are returned as errors")]
     Failed,
 }
 
-enum GrantType<'a> {
+/// How to get the access token of a request.
+pub(crate) enum TokenAction {
+    /// Use this access token.
+    Use(String),
+    /// Renew the token with this refresh token first.
+    Refresh(String),
+}
+
+pub(crate) enum GrantType<'a> {
     DeviceCode(&'a str),
     RefreshToken(&'a str),
 }
 
 #[derive(Deserialize)]
-struct AuthResponse {
+pub(crate) struct AuthResponse {
     device_code: String,
     user_code: String,
     verification_uri: String,
@@ -62,36 +135,65 @@ struct AuthErrorMessage {
     error_description: String,
 }
 
-enum AuthError {
+pub(crate) enum AuthError {
     Pending,
     SlowDown,
-    Failed,
+    Failed(Error),
 }
 
 #[derive(Deserialize, Serialize)]
-struct DeviceList {
-    devices: Vec<Device>,
+pub(crate) struct DeviceList {
+    pub(crate) devices: Vec<Device>,
 }
 
 #[derive(Deserialize, Serialize)]
-struct CardList {
-    cards: Vec<Card>,
+pub(crate) struct CardList {
+    pub(crate) cards: Vec<Card>,
 }
 
 #[derive(Deserialize, Serialize)]
-struct ImageList {
-    images: Vec<Image>,
+pub(crate) struct ImageList {
+    pub(crate) images: Vec<Image>,
 }
 
 #[derive(Deserialize, Serialize)]
-struct IconList {
+pub(crate) struct IconList {
     #[serde(rename = "displayIcons")]
-    icons: Vec<DisplayIcon>,
+    pub(crate) icons: Vec<DisplayIcon>,
 }
 
 #[derive(Deserialize, Serialize)]
-struct ContentResponse {
-    card: Card,
+pub(crate) struct ContentResponse {
+    pub(crate) card: Card,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConfiguredDevice {
+    pub(crate) config: DeviceConfig,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceConfigResponse {
+    pub(crate) device: ConfiguredDevice,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeviceConfigUpdate<'a> {
+    pub(crate) device_id: &'a str,
+    pub(crate) config: &'a DeviceConfig,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UploadedIcon {
+    #[serde(rename = "mediaId")]
+    pub(crate) media_id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IconUploadResponse {
+    #[serde(rename = "displayIcon")]
+    pub(crate) icon: UploadedIcon,
 }
 
 #[derive(Deserialize)]
@@ -103,288 +205,1163 @@ pub struct Upload {
 }
 
 #[derive(Deserialize)]
-struct UploadResponse {
-    upload: Upload,
+pub(crate) struct UploadResponse {
+    pub(crate) upload: Upload,
 }
 
 #[derive(Deserialize)]
-struct TranscodedAudio {
+pub(crate) struct TranscodedAudio {
     #[serde(rename = "transcodedSha256")]
-    uri: Option<String>,
+    pub(crate) uri: Option<String>,
+    #[serde(rename = "transcodedInfo")]
+    pub(crate) info: Option<TranscodedInfo>,
+}
+
+/// Properties of transcoded audio.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscodedInfo {
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub file_size: Option<u64>,
+    pub format: Option<String>,
+    pub channels: Option<String>,
+}
+
+/// Audio uploaded and transcoded by the server.
+#[derive(Clone, Debug)]
+pub struct Transcoded {
+    pub sha256: String,
+    pub info: TranscodedInfo,
+}
+
+impl Transcoded {
+    /// Creates a track playing this audio, with its duration and format.
+    pub fn track(&self, key: &str, title: &str) -> Track {
+        let format = self
+            .info
+            .format
+            .as_deref()
+            .and_then(|f| MediaFormat::from_ext(f).ok())
+            .unwrap_or(MediaFormat::Aac);
+        let mut track = Track::new(key, title, &media_url(&self.sha256), format)
+            .duration(self.info.duration.unwrap_or(0.0).round() as u64)
+            .file_size(self.info.file_size.unwrap_or(0));
+        match self.info.channels.as_deref() {
+            Some("mono") => track = track.channels(ChannelType::Mono),
+            Some("stereo") => track = track.channels(ChannelType::Stereo),
+            _ => (),
+        }
+        track
+    }
 }
 
 #[derive(Deserialize)]
-struct TranscodeResponse {
-    transcode: TranscodedAudio,
+pub(crate) struct TranscodeResponse {
+    pub(crate) transcode: TranscodedAudio,
 }
 
-static TOKEN_URL: &str = "https://login.yotoplay.com/oauth/token";
-static AUTH_URL: &str = "https://login.yotoplay.com/oauth/device/code";
-static BASE_URL: &str = "https://api.yotoplay.com";
+pub static TOKEN_URL: &str = "https://login.yotoplay.com/oauth/token";
+pub static AUTH_URL: &str = "https://login.yotoplay.com/oauth/device/code";
+pub static BASE_URL: &str = "https://api.yotoplay.com";
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Number of times the transcoding status is polled after an upload.
+pub(crate) const TRANSCODE_ATTEMPTS: u32 = 30;
+pub(crate) const TRANSCODE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// REST endpoints, relative to the base URL.
+pub(crate) mod endpoint {
+    pub const DEVICES: &str = "/device-v2/devices/mine";
+    pub const CARDS: &str = "/content/mine";
+    pub const CONTENT: &str = "/content";
+    pub const FAMILY_IMAGES: &str = "/media/family/images";
+    pub const DISPLAY_ICONS: &str = "/media/displayIcons/user/me";
+    pub const PUBLIC_DISPLAY_ICONS: &str = "/media/displayIcons/user/yoto";
+    pub const DISPLAY_ICON_UPLOAD: &str = "/media/displayIcons/user/me/upload";
+    pub const AUDIO_UPLOAD_URL: &str = "/media/transcode/audio/uploadUrl";
+
+    pub fn device_status(id: &str) -> String {
+        format!("/device-v2/{}/status", id)
+    }
+
+    pub fn device_config(id: &str) -> String {
+        format!("/device-v2/{}/config", id)
+    }
+
+    pub fn card(id: &str) -> String {
+        format!("/content/{}", id)
+    }
+
+    pub fn transcoded(upload_id: &str) -> String {
+        format!("/media/upload/{}/transcoded?loudnorm=false", upload_id)
+    }
+}
 
 impl Token {
     pub fn is_expired(&self) -> bool {
         let expiration = Utc::now() + TimeDelta::seconds(30);
-        return self.valid_until < expiration;
+        self.valid_until < expiration
+    }
+
+    pub fn access_token(&self) -> &str {
+        &self.access_token
     }
 }
 
-impl Client {
-    pub fn new(client_id: &str, token: Option<Token>) -> Client {
-        Client {
+impl DeviceLogin {
+    pub fn is_expired(&self) -> bool {
+        Utc::now() >= self.expires_at
+    }
+
+    pub(crate) fn device_code(&self) -> &str {
+        &self.device_code
+    }
+}
+
+impl From<AuthResponse> for DeviceLogin {
+    fn from(response: AuthResponse) -> DeviceLogin {
+        DeviceLogin {
+            user_code: response.user_code,
+            verification_uri: response.verification_uri,
+            verification_uri_complete: response.verification_uri_complete,
+            expires_at: Utc::now() + TimeDelta::seconds(response.expires_in),
+            interval: Duration::from_secs(response.interval),
+            device_code: response.device_code,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            base_url: BASE_URL.to_string(),
+            auth_url: AUTH_URL.to_string(),
+            token_url: TOKEN_URL.to_string(),
+            audience: BASE_URL.to_string(),
+            timeout: Some(Duration::from_secs(30)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            user_agent: USER_AGENT.to_string(),
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new("", None)
+    }
+}
+
+impl ClientBuilder {
+    pub fn new(client_id: &str) -> ClientBuilder {
+        ClientBuilder {
             id: client_id.to_string(),
-            token,
-            client: reqwest::blocking::Client::new(),
+            token: None,
+            store: None,
+            on_token_refresh: None,
+            config: Config::default(),
         }
     }
 
-    pub fn auth(&mut self) -> Result<(), String> {
-        let mut data = HashMap::new();
-        data.insert("client_id", self.id.as_ref());
-        data.insert("scope", "profile");
-        data.insert("audience", BASE_URL);
+    pub fn token(mut self, token: Option<Token>) -> ClientBuilder {
+        self.token = token;
+        self
+    }
 
-        let response = self
-            .client
-            .post(AUTH_URL)
-            .form(&data)
-            .send()
-            .unwrap()
-            .json::<AuthResponse>()
-            .unwrap();
-        println!("User Code: {}", response.user_code);
-        println!("Verification: {}", response.verification_uri_complete);
+    /// Store used to load the initial token, if none was given, and to save
+    /// new tokens.
+    pub fn token_store(mut self, store: Arc<dyn TokenStore>) -> ClientBuilder {
+        self.store = Some(store);
+        self
+    }
 
-        let mut interval = response.interval;
-        loop {
-            let result = self.request_token(GrantType::DeviceCode(&response.device_code));
-            match result {
-                Ok(token) => {
-                    self.token = Some(token);
-                    return Ok(());
-                }
-                Err(AuthError::Pending) => {
-                    sleep(Duration::from_secs(interval));
-                }
-                Err(AuthError::SlowDown) => {
-                    interval += 5;
-                    sleep(Duration::from_secs(interval));
-                }
-                Err(_) => return Err("Failed to authenticate".to_string()),
-            }
+    pub fn on_token_refresh<F>(mut self, callback: F) -> ClientBuilder
+    where
+        F: Fn(&Token, Option<&Error>) + Send + Sync + 'static,
+    {
+        self.on_token_refresh = Some(Box::new(callback));
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> ClientBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn base_url(mut self, url: impl Into<String>) -> ClientBuilder {
+        self.config.base_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn auth_url(mut self, url: impl Into<String>) -> ClientBuilder {
+        self.config.auth_url = url.into();
+        self
+    }
+
+    pub fn token_url(mut self, url: impl Into<String>) -> ClientBuilder {
+        self.config.token_url = url.into();
+        self
+    }
+
+    pub fn audience(mut self, audience: impl Into<String>) -> ClientBuilder {
+        self.config.audience = audience.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> ClientBuilder {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> ClientBuilder {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> ClientBuilder {
+        self.config.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(&self.config.user_agent)
+            .timeout(self.config.timeout);
+        if let Some(timeout) = self.config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
         }
+        let token = match (self.token, &self.store) {
+            (None, Some(store)) => store.load()?,
+            (token, _) => token,
+        };
+        Ok(Client {
+            id: self.id,
+            token: Mutex::new(token),
+            store: self.store,
+            on_token_refresh: self.on_token_refresh,
+            client: builder.build()?,
+            config: self.config,
+        })
     }
+}
 
-    pub fn refresh_token(&mut self) -> RefreshStatus {
-        match &self.token {
-            Some(token) => {
-                if !token.is_expired() {
-                    return RefreshStatus::AlreadyValid;
-                }
+impl Client {
+    /// Creates a client with the default configuration.
+    ///
+    /// Panics if the HTTP client cannot be initialized, like
+    /// [`reqwest::blocking::Client::new`]; use [`Client::builder`] to handle
+    /// that error instead.
+    pub fn new(client_id: &str, token: Option<Token>) -> Client {
+        Client::builder(client_id)
+            .token(token)
+            .build()
+            .expect("failed to initialize the HTTP client")
+    }
 
-                if let Ok(token) = self.request_token(GrantType::RefreshToken(&token.refresh_token))
-                {
-                    self.token = Some(token);
-                    RefreshStatus::Refreshed
-                } else {
-                    RefreshStatus::Failed
-                }
-            }
-            None => RefreshStatus::Failed,
+    pub fn builder(client_id: &str) -> ClientBuilder {
+        ClientBuilder::new(client_id)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns a copy of the current token, if authenticated.
+    pub fn token(&self) -> Option<Token> {
+        self.lock_token().clone()
+    }
+
+    pub fn set_token(&self, token: Option<Token>) {
+        *self.lock_token() = token;
+    }
+
+    /// Clears the current token, both in memory and in the token store.
+    pub fn logout(&self) -> Result<()> {
+        self.set_token(None);
+        match &self.store {
+            Some(store) => store.clear(),
+            None => Ok(()),
         }
     }
 
-    fn request_token(&self, grant_type: GrantType) -> Result<Token, AuthError> {
-        let mut data = HashMap::new();
-        data.insert("client_id", self.id.as_ref());
+    pub fn on_token_refresh<F>(&mut self, callback: F)
+    where
+        F: Fn(&Token, Option<&Error>) + Send + Sync + 'static,
+    {
+        self.on_token_refresh = Some(Box::new(callback));
+    }
 
-        match grant_type {
-            GrantType::DeviceCode(code) => {
-                println!("Polling for new token");
-                data.insert("grant_type", "urn:ietf:params:oauth:grant-type:device_code");
-                data.insert("device_code", code);
-                data.insert("audience", BASE_URL);
-            }
-            GrantType::RefreshToken(token) => {
-                println!("Requesting refresh token");
-                data.insert("grant_type", "refresh_token");
-                data.insert("refresh_token", token);
-            }
-        };
+    fn lock_token(&self) -> MutexGuard<'_, Option<Token>> {
+        self.token.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}{}", self.config.base_url, endpoint)
+    }
 
-        let response = self.client.post(TOKEN_URL).form(&data).send().unwrap();
+    /// Starts a device code login.
+    pub fn start_login(&self) -> Result<DeviceLogin> {
+        let data = login_form(&self.id, &self.config);
+        let response = self.client.post(&self.config.auth_url).form(&data).send()?;
+        Ok(parse_response::<AuthResponse>(response)?.into())
+    }
 
-        match response.status() {
-            StatusCode::OK => {
-                let mut token = response.json::<Token>().unwrap();
-                token.valid_until = Utc::now() + TimeDelta::seconds(token.expires_in);
-                println!("New token valid for {} seconds", token.expires_in);
-                Ok(token)
+    /// Polls the token endpoint once, without waiting.
+    ///
+    /// On completion the new token is installed in the client and saved to
+    /// the token store.
+    pub fn poll_login(&self, login: &mut DeviceLogin) -> Result<LoginStatus> {
+        if login.is_expired() {
+            return Err(Error::LoginExpired);
+        }
+        let result = self.request_token(GrantType::DeviceCode(login.device_code()));
+        match login_status(login, result)? {
+            (status, Some(token)) => {
+                if let Some(store) = &self.store {
+                    store.save(&token)?;
+                }
+                self.set_token(Some(token));
+                Ok(status)
             }
-            StatusCode::FORBIDDEN => match response.json::<AuthErrorMessage>() {
-                Ok(error) => match error.error.as_ref() {
-                    "authorization_pending" => Err(AuthError::Pending),
-                    "slow_down" => Err(AuthError::SlowDown),
-                    err => {
-                        println!("Err: {}", err);
-                        Err(AuthError::Failed)
-                    }
-                },
-                _ => Err(AuthError::Failed),
-            },
-            err => {
-                println!("Err {}", err);
-                Err(AuthError::Failed)
+            (status, None) => Ok(status),
+        }
+    }
+
+    /// Polls the login at the server interval until it completes or fails.
+    ///
+    /// `on_poll` is called after every poll; returning `false` cancels the
+    /// login.
+    pub fn wait_login<F>(&self, login: &mut DeviceLogin, mut on_poll: F) -> Result<()>
+    where
+        F: FnMut(&DeviceLogin, LoginStatus) -> bool,
+    {
+        loop {
+            let status = self.poll_login(login)?;
+            if !on_poll(login, status) {
+                return Err(Error::LoginCancelled);
+            }
+            if status == LoginStatus::Complete {
+                return Ok(());
             }
+            sleep(login.interval);
         }
     }
 
-    fn ensure_token(&self) -> Option<&Token> {
-        let token = self.token.as_ref().expect("Not authenticated");
-        if token.is_expired() {
-            None
+    /// Refreshes the token if it is about to expire.
+    pub fn refresh_token(&self) -> Result<RefreshStatus> {
+        let previous = self.token().ok_or(Error::NotAuthenticated)?;
+        if self.access_token(None)? == previous.access_token {
+            Ok(RefreshStatus::AlreadyValid)
         } else {
-            Some(token)
+            Ok(RefreshStatus::Refreshed)
+        }
+    }
+
+    /// Saves a refreshed token to the store and notifies the refresh
+    /// callback, which may call the client again.
+    ///
+    /// A store failure must not fail the request that triggered the refresh,
+    /// so it is only reported to the callback.
+    fn token_refreshed(&self, token: &Token) {
+        let saved = match &self.store {
+            Some(store) => store.save(token),
+            None => Ok(()),
+        };
+        if let Some(callback) = &self.on_token_refresh {
+            callback(token, saved.as_ref().err());
         }
     }
 
-    pub fn get_objects<T: DeserializeOwned>(&self, endpoint: &str) -> T {
-        let token = self.ensure_token().unwrap();
-        let url = format!("{}{}", BASE_URL, endpoint);
-        self.client
-            .get(url)
-            .bearer_auth(&token.access_token)
+    fn request_token(&self, grant_type: GrantType) -> std::result::Result<Token, AuthError> {
+        let data = token_form(&self.id, &self.config, grant_type);
+        let response = self
+            .client
+            .post(&self.config.token_url)
+            .form(&data)
             .send()
-            .unwrap()
-            .json::<T>()
-            .unwrap()
+            .map_err(|e| AuthError::Failed(e.into()))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().map_err(|e| AuthError::Failed(e.into()))?;
+        parse_token(status, &headers, body)
+    }
+
+    /// Returns a valid access token, renewing it first if it is about to
+    /// expire or if the server rejected it.
+    fn access_token(&self, rejected: Option<&str>) -> Result<String> {
+        let token = {
+            /* Held during the refresh, so that concurrent requests wait for
+             * it rather than refreshing again */
+            let mut slot = self.lock_token();
+            let refresh_token = match token_action(slot.as_ref(), rejected)? {
+                TokenAction::Use(access_token) => return Ok(access_token),
+                TokenAction::Refresh(refresh_token) => refresh_token,
+            };
+            let result = self.request_token(GrantType::RefreshToken(&refresh_token));
+            let token = refreshed_token(&refresh_token, result)?;
+            *slot = Some(token.clone());
+            token
+        };
+        self.token_refreshed(&token);
+        Ok(token.access_token)
+    }
+
+    /// Sends an authenticated request, retrying once with a refreshed token
+    /// if the server rejects the current one.
+    fn send_authorized<F>(&self, request: F) -> Result<Response>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        let access_token = self.access_token(None)?;
+        let response = request(&access_token).send()?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let access_token = self.access_token(Some(&access_token))?;
+        Ok(request(&access_token).send()?)
+    }
+
+    pub fn get_objects<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T> {
+        self.get_object(endpoint, None)
     }
 
     pub fn get_object<T: DeserializeOwned>(
         &self,
         endpoint: impl AsRef<str>,
         params: Option<&HashMap<&str, &str>>,
-    ) -> T {
-        let token = self.ensure_token().unwrap();
-        let url = format!("{}{}", BASE_URL, endpoint.as_ref());
-        let mut builder = self.client.get(url).bearer_auth(&token.access_token);
-        if let Some(p) = params {
-            builder = builder.query(p);
-        }
-        builder.send().unwrap().json::<T>().unwrap()
+    ) -> Result<T> {
+        let url = self.url(endpoint.as_ref());
+        let response = self.send_authorized(|token| {
+            let builder = self.client.get(&url).bearer_auth(token);
+            match params {
+                Some(p) => builder.query(p),
+                None => builder,
+            }
+        })?;
+        parse_response(response)
     }
 
-    pub fn delete_object(&self, endpoint: impl AsRef<str>) {
-        let token = self.ensure_token().unwrap();
-        let url = format!("{}{}", BASE_URL, endpoint.as_ref());
-        self.client
-            .delete(url)
-            .bearer_auth(&token.access_token)
-            .send()
-            .unwrap();
+    pub fn post_object<T: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        endpoint: impl AsRef<str>,
+        body: &T,
+    ) -> Result<R> {
+        let url = self.url(endpoint.as_ref());
+        let response =
+            self.send_authorized(|token| self.client.post(&url).bearer_auth(token).json(body))?;
+        parse_response(response)
     }
 
-    pub fn get_devices(&self) -> Vec<Device> {
-        self.get_objects::<DeviceList>("/device-v2/devices/mine")
-            .devices
+    pub fn delete_object(&self, endpoint: impl AsRef<str>) -> Result<()> {
+        let url = self.url(endpoint.as_ref());
+        let response = self.send_authorized(|token| self.client.delete(&url).bearer_auth(token))?;
+        check_response(response).map(|_| ())
     }
 
-    pub fn get_device_status(&self, id: &str) -> DeviceStatus {
-        self.get_object::<DeviceStatus>(format!("/device-v2/{}/status", id), None)
+    pub fn get_devices(&self) -> Result<Vec<Device>> {
+        Ok(self.get_objects::<DeviceList>(endpoint::DEVICES)?.devices)
     }
 
-    pub fn get_cards(&self) -> Vec<Card> {
-        self.get_objects::<CardList>("/content/mine").cards
+    pub fn get_device_status(&self, id: &str) -> Result<DeviceStatus> {
+        self.get_object::<DeviceStatus>(endpoint::device_status(id), None)
     }
 
-    pub fn get_card(&self, id: &str, playable: bool) -> Result<Card, ClientError> {
-        let endpoint = format!("/content/{}", id);
-        let mut params = HashMap::new();
-        if playable {
-            params.insert("playable", "true");
-            params.insert("signingType", "s3");
-        }
+    pub fn get_device_config(&self, id: &str) -> Result<DeviceConfig> {
+        Ok(self
+            .get_object::<DeviceConfigResponse>(endpoint::device_config(id), None)?
+            .device
+            .config)
+    }
+
+    /// Updates the settings of a player. To keep the settings this library
+    /// does not know about, pass a configuration obtained from
+    /// [`Client::get_device_config`].
+    pub fn update_device_config(&self, id: &str, config: &DeviceConfig) -> Result<()> {
+        let url = self.url(&endpoint::device_config(id));
+        let body = DeviceConfigUpdate {
+            device_id: id,
+            config,
+        };
+        let response =
+            self.send_authorized(|token| self.client.post(&url).bearer_auth(token).json(&body))?;
+        check_response(response).map(|_| ())
+    }
+
+    pub fn get_cards(&self) -> Result<Vec<Card>> {
+        Ok(self.get_objects::<CardList>(endpoint::CARDS)?.cards)
+    }
+
+    pub fn get_card(&self, id: &str, playable: bool) -> Result<Card> {
+        let params = card_params(playable);
         Ok(self
-            .get_object::<ContentResponse>(endpoint, Some(&params))
+            .get_object::<ContentResponse>(endpoint::card(id), Some(&params))?
             .card)
     }
 
-    pub fn delete_card(&self, id: &str) {
-        let endpoint = format!("/content/{}", id);
-        self.delete_object(endpoint);
+    /// Creates a card, or updates it if its ID is set, returning the card as
+    /// stored by the server.
+    pub fn save_card(&self, card: &Card) -> Result<Card> {
+        Ok(self
+            .post_object::<_, ContentResponse>(endpoint::CONTENT, card)?
+            .card)
     }
 
-    pub fn get_family_images(&self) -> Vec<Image> {
-        self.get_objects::<ImageList>("/media/family/images").images
+    /// Creates a new card, ignoring its ID if set, and returns it with the ID
+    /// assigned by the server.
+    pub fn create_card(&self, card: &Card) -> Result<Card> {
+        Ok(self
+            .post_object::<_, ContentResponse>(endpoint::CONTENT, &new_card(card)?)?
+            .card)
     }
 
-    fn request_audio_upload_url(&self) -> Upload {
-        let url = format!("{}/media/transcode/audio/uploadUrl", BASE_URL);
-        let token = &self.token.as_ref().unwrap().access_token;
-        let response = self.client.get(url).bearer_auth(token).send().unwrap();
-        response.json::<UploadResponse>().unwrap().upload
+    /// Replaces the content of an existing card.
+    pub fn update_card(&self, card: &Card) -> Result<Card> {
+        check_card_id(card)?;
+        self.save_card(card)
     }
 
-    fn send_audio_file(&self, path: &Path, upload: &Upload) -> Result<(), String> {
-        let ext = path
-            .extension()
-            .ok_or("File without extesnion")?
-            .to_str()
-            .unwrap();
-        let format = MediaFormat::from_ext(ext)?;
+    pub fn delete_card(&self, id: &str) -> Result<()> {
+        self.delete_object(endpoint::card(id))
+    }
+
+    pub fn get_family_images(&self) -> Result<Vec<Image>> {
+        Ok(self
+            .get_objects::<ImageList>(endpoint::FAMILY_IMAGES)?
+            .images)
+    }
+
+    pub fn get_display_icons(&self) -> Result<Vec<DisplayIcon>> {
+        Ok(self.get_objects::<IconList>(endpoint::DISPLAY_ICONS)?.icons)
+    }
+
+    /// Lists the display icons provided by Yoto to all users.
+    pub fn get_public_display_icons(&self) -> Result<Vec<DisplayIcon>> {
+        Ok(self
+            .get_objects::<IconList>(endpoint::PUBLIC_DISPLAY_ICONS)?
+            .icons)
+    }
+
+    fn request_audio_upload_url(&self) -> Result<Upload> {
+        Ok(self
+            .get_objects::<UploadResponse>(endpoint::AUDIO_UPLOAD_URL)?
+            .upload)
+    }
+
+    fn send_audio_file(&self, path: &Path, upload: &Upload) -> Result<()> {
         let mut headers = HeaderMap::new();
-        headers.insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, audio_content_type(path)?);
 
-        let data: Vec<u8> = fs::read(path).unwrap();
+        let data: Vec<u8> = fs::read(path)?;
         let response = self
             .client
             .put(&upload.url)
             .headers(headers)
             .body(data)
-            .send()
-            .unwrap();
-
-        match response.status() {
-            StatusCode::OK => Ok(()),
-            _ => Err("Failed to upload file".to_string()),
-        }
+            .send()?;
+        check_response(response).map(|_| ())
     }
 
-    fn wait_audio_transcode(&self, upload: &Upload) -> Result<String, String> {
+    fn wait_audio_transcode(&self, upload: &Upload) -> Result<Transcoded> {
         let mut headers = HeaderMap::new();
-        headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+        headers.insert(
+            header::ACCEPT,
+            header::HeaderValue::from_static("application/json"),
+        );
 
         let mut attempts = 0;
-        let url = format!(
-            "{}/media/upload/{}/transcoded?loudnorm=false",
-            BASE_URL, &upload.id
-        );
-        let token = &self.token.as_ref().unwrap().access_token;
-        let request = self
-            .client
-            .get(&url)
-            .bearer_auth(token)
-            .headers(headers)
-            .build()
-            .unwrap();
+        let url = self.url(&endpoint::transcoded(&upload.id));
 
         loop {
-            let response = self.client.execute(request.try_clone().unwrap()).unwrap();
-            let audio = response.json::<TranscodeResponse>().unwrap().transcode;
-            if audio.uri.is_some() {
-                return Ok(audio.uri.unwrap());
+            let response = self.send_authorized(|token| {
+                self.client
+                    .get(&url)
+                    .bearer_auth(token)
+                    .headers(headers.clone())
+            })?;
+            let audio = parse_response::<TranscodeResponse>(response)?.transcode;
+            if let Some(sha256) = audio.uri {
+                return Ok(Transcoded {
+                    sha256,
+                    info: audio.info.unwrap_or_default(),
+                });
             }
             attempts += 1;
-            if attempts >= 30 {
-                return Err("Error transcoding".to_string());
+            if attempts >= TRANSCODE_ATTEMPTS {
+                return Err(Error::TranscodeTimeout);
             }
-            sleep(Duration::from_millis(500));
+            sleep(TRANSCODE_INTERVAL);
         }
     }
 
-    pub fn upload_audio_file(&self, path: &Path) -> Result<String, String> {
-        let upload = self.request_audio_upload_url();
+    /// Uploads an audio file and waits for it to be transcoded.
+    pub fn upload_audio(&self, path: &Path) -> Result<Transcoded> {
+        let upload = self.request_audio_upload_url()?;
         self.send_audio_file(path, &upload)?;
         self.wait_audio_transcode(&upload)
     }
+
+    /// Uploads an audio file, returning the SHA-256 of the transcoded audio.
+    pub fn upload_audio_file(&self, path: &Path) -> Result<String> {
+        Ok(self.upload_audio(path)?.sha256)
+    }
+
+    /// Uploads a custom display icon, returning its media ID.
+    pub fn upload_display_icon(&self, data: Vec<u8>, filename: &str) -> Result<String> {
+        let url = self.url(endpoint::DISPLAY_ICON_UPLOAD);
+        let params = icon_upload_params(filename);
+        let response = self.send_authorized(|token| {
+            self.client
+                .post(&url)
+                .bearer_auth(token)
+                .query(&params)
+                .body(data.clone())
+        })?;
+        Ok(parse_response::<IconUploadResponse>(response)?
+            .icon
+            .media_id)
+    }
+
+    /// Downloads a signed media URL, as found in playable cards.
+    pub fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let response = check_response(self.client.get(url).send()?)?;
+        Ok(response.bytes()?.to_vec())
+    }
+}
+
+pub(crate) fn login_form<'a>(id: &'a str, config: &'a Config) -> HashMap<&'static str, &'a str> {
+    let mut data = HashMap::new();
+    data.insert("client_id", id);
+    data.insert("scope", "profile");
+    data.insert("audience", config.audience.as_ref());
+    data
+}
+
+pub(crate) fn token_form<'a>(
+    id: &'a str,
+    config: &'a Config,
+    grant_type: GrantType<'a>,
+) -> HashMap<&'static str, &'a str> {
+    let mut data = HashMap::new();
+    data.insert("client_id", id);
+
+    match grant_type {
+        GrantType::DeviceCode(code) => {
+            data.insert("grant_type", "urn:ietf:params:oauth:grant-type:device_code");
+            data.insert("device_code", code);
+            data.insert("audience", config.audience.as_ref());
+        }
+        GrantType::RefreshToken(token) => {
+            data.insert("grant_type", "refresh_token");
+            data.insert("refresh_token", token);
+        }
+    };
+    data
+}
+
+/// Interprets a response of the token endpoint.
+pub(crate) fn parse_token(
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: String,
+) -> std::result::Result<Token, AuthError> {
+    match status {
+        StatusCode::OK => {
+            let mut token =
+                serde_json::from_str::<Token>(&body).map_err(|e| AuthError::Failed(e.into()))?;
+            token.valid_until = Utc::now() + TimeDelta::seconds(token.expires_in);
+            Ok(token)
+        }
+        StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            match serde_json::from_str::<AuthErrorMessage>(&body) {
+                Ok(error) => match error.error.as_ref() {
+                    "authorization_pending" => Err(AuthError::Pending),
+                    "slow_down" => Err(AuthError::SlowDown),
+                    "expired_token" => Err(AuthError::Failed(Error::LoginExpired)),
+                    "access_denied" => Err(AuthError::Failed(Error::AccessDenied)),
+                    err if error.error_description.is_empty() => {
+                        Err(AuthError::Failed(Error::Auth(err.to_string())))
+                    }
+                    err => Err(AuthError::Failed(Error::Auth(format!(
+                        "{}: {}",
+                        err, error.error_description
+                    )))),
+                },
+                Err(_) => Err(AuthError::Failed(Error::Http { status, body })),
+            }
+        }
+        _ => Err(AuthError::Failed(status_error(status, headers, body))),
+    }
+}
+
+/// Maps the result of a device code poll to a login status, along with the
+/// new token once the login is complete.
+pub(crate) fn login_status(
+    login: &mut DeviceLogin,
+    result: std::result::Result<Token, AuthError>,
+) -> Result<(LoginStatus, Option<Token>)> {
+    match result {
+        Ok(token) => Ok((LoginStatus::Complete, Some(token))),
+        Err(AuthError::Pending) => Ok((LoginStatus::Pending, None)),
+        Err(AuthError::SlowDown) => {
+            login.interval += Duration::from_secs(5);
+            Ok((LoginStatus::SlowDown, None))
+        }
+        Err(AuthError::Failed(err)) => Err(err),
+    }
+}
+
+/// Decides how to get an access token from the current token. `rejected` is
+/// the access token the server just refused, if any.
+pub(crate) fn token_action(current: Option<&Token>, rejected: Option<&str>) -> Result<TokenAction> {
+    let current = current.ok_or(Error::NotAuthenticated)?;
+    let usable = match rejected {
+        /* Another request may have refreshed it in the meantime */
+        Some(rejected) => current.access_token != rejected,
+        None => !current.is_expired(),
+    };
+    if usable {
+        Ok(TokenAction::Use(current.access_token.clone()))
+    } else if current.refresh_token.is_empty() {
+        Err(Error::AuthExpired)
+    } else {
+        Ok(TokenAction::Refresh(current.refresh_token.clone()))
+    }
+}
+
+/// Maps the result of a refresh request, made with `refresh_token`, to the
+/// new token.
+pub(crate) fn refreshed_token(
+    refresh_token: &str,
+    result: std::result::Result<Token, AuthError>,
+) -> Result<Token> {
+    let mut token = match result {
+        Ok(token) => token,
+        Err(AuthError::Failed(Error::Auth(_) | Error::AccessDenied)) => {
+            return Err(Error::AuthExpired)
+        }
+        Err(AuthError::Failed(err)) => return Err(err),
+        Err(_) => return Err(Error::AuthExpired),
+    };
+    /* Refresh tokens are not always rotated */
+    if token.refresh_token.is_empty() {
+        token.refresh_token = refresh_token.to_string();
+    }
+    Ok(token)
+}
+
+pub(crate) fn card_params(playable: bool) -> HashMap<&'static str, &'static str> {
+    let mut params = HashMap::new();
+    if playable {
+        params.insert("playable", "true");
+        params.insert("signingType", "s3");
+    }
+    params
+}
+
+/// Serializes a card without its ID, so that a new one gets created.
+pub(crate) fn new_card(card: &Card) -> Result<serde_json::Value> {
+    let mut value = serde_json::to_value(card)?;
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("cardId");
+    }
+    Ok(value)
+}
+
+pub(crate) fn check_card_id(card: &Card) -> Result<()> {
+    if card.card_id.is_empty() {
+        return Err(Error::InvalidCard(
+            "updating a card requires its ID".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn icon_upload_params(filename: &str) -> [(&'static str, &str); 2] {
+    [("autoConvert", "true"), ("filename", filename)]
+}
+
+pub(crate) fn audio_content_type(path: &Path) -> Result<header::HeaderValue> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_else(|| Error::UnsupportedFormat(path.display().to_string()))?;
+    let format = MediaFormat::from_ext(ext)?;
+    header::HeaderValue::from_str(&format.content_type())
+        .map_err(|_| Error::UnsupportedFormat(ext.to_string()))
+}
+
+pub(crate) fn status_error(status: StatusCode, headers: &HeaderMap, body: String) -> Error {
+    match status {
+        StatusCode::UNAUTHORIZED => Error::AuthExpired,
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = headers
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            Error::RateLimited { retry_after }
+        }
+        _ => Error::Http { status, body },
+    }
+}
+
+fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let headers = response.headers().clone();
+        Err(status_error(
+            status,
+            &headers,
+            response.text().unwrap_or_default(),
+        ))
+    }
+}
+
+fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let body = check_response(response)?.text()?;
+    Ok(serde_json::from_str::<T>(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::testing::{
+        client, serve, serve_with_status, token, token_client, token_server, ReadOnlyStore,
+    };
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::OnceLock;
+
+    /// Answers a single request with an empty body, returning its headers.
+    fn request_headers() -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_ascii_lowercase());
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            headers
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn builder_overrides_endpoints() {
+        let client = Client::builder("id")
+            .base_url("http://localhost:8080/")
+            .auth_url("http://localhost:8080/device")
+            .token_url("http://localhost:8080/token")
+            .audience("test")
+            .timeout(None)
+            .user_agent("agent/1.0")
+            .build()
+            .unwrap();
+        let config = client.config();
+        assert_eq!(config.base_url, "http://localhost:8080");
+        assert_eq!(config.auth_url, "http://localhost:8080/device");
+        assert_eq!(config.token_url, "http://localhost:8080/token");
+        assert_eq!(config.audience, "test");
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.connect_timeout, Config::default().connect_timeout);
+        assert_eq!(config.user_agent, "agent/1.0");
+
+        let config = Client::new("id", None).config().clone();
+        assert_eq!(config.base_url, BASE_URL);
+        assert_eq!(config.token_url, TOKEN_URL);
+    }
+
+    #[test]
+    fn requests_go_to_base_url() {
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let url = {
+            let paths = paths.clone();
+            serve(move |_, path, _| {
+                paths.lock().unwrap().push(path.to_string());
+                json!({ "devices": [{
+                    "deviceId": "abc", "name": "Kitchen", "description": "", "online": true
+                }] })
+                .to_string()
+                .into_bytes()
+            })
+        };
+        let devices = client(&format!("{}/", url)).get_devices().unwrap();
+        assert_eq!(devices[0].name, "Kitchen");
+        assert_eq!(*paths.lock().unwrap(), vec![endpoint::DEVICES]);
+    }
+
+    #[test]
+    fn new_sends_user_agent() {
+        let (url, handle) = request_headers();
+        Client::new("id", None).download(&url).unwrap();
+        let headers = handle.join().unwrap();
+        assert!(headers.contains(&format!("user-agent: {}", USER_AGENT.to_ascii_lowercase())));
+
+        let (url, handle) = request_headers();
+        let client = Client::builder("id")
+            .user_agent("agent/1.0")
+            .build()
+            .unwrap();
+        client.download(&url).unwrap();
+        assert!(handle
+            .join()
+            .unwrap()
+            .contains(&"user-agent: agent/1.0".to_string()));
+    }
+
+    #[test]
+    fn status_errors() {
+        let mut headers = HeaderMap::new();
+        let error = |status: u16, headers: &HeaderMap| {
+            status_error(
+                StatusCode::from_u16(status).unwrap(),
+                headers,
+                "body".to_string(),
+            )
+        };
+        assert!(matches!(error(401, &headers), Error::AuthExpired));
+        assert!(matches!(error(404, &headers), Error::NotFound));
+        assert!(matches!(
+            error(429, &headers),
+            Error::RateLimited { retry_after: None }
+        ));
+        headers.insert(header::RETRY_AFTER, "30".parse().unwrap());
+        assert!(matches!(
+            error(429, &headers),
+            Error::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            error(500, &headers),
+            Error::Http { status, body } if status == 500 && body == "body"
+        ));
+    }
+
+    #[test]
+    fn responses_map_to_errors() {
+        let url = serve_with_status(|_, path, _| match path.split('?').next().unwrap() {
+            "/content/missing" => (404, b"{}".to_vec()),
+            "/content/broken" => (500, b"oops".to_vec()),
+            "/content/garbled" => (200, b"not json".to_vec()),
+            _ => (401, b"{}".to_vec()),
+        });
+        let client = client(&url);
+        assert!(matches!(
+            client.get_card("missing", false),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            client.get_card("broken", false),
+            Err(Error::Http { status, body }) if status == 500 && body == "oops"
+        ));
+        assert!(matches!(
+            client.get_card("garbled", false),
+            Err(Error::Decode(_))
+        ));
+        /* Without a refresh token, a rejected token cannot be renewed */
+        assert!(matches!(client.get_cards(), Err(Error::AuthExpired)));
+        assert!(matches!(
+            Client::new("id", None).get_cards(),
+            Err(Error::NotAuthenticated)
+        ));
+    }
+
+    #[test]
+    fn refresh_before_request() {
+        let (url, requests) = token_server(0);
+        let client = token_client(&url, token(-TimeDelta::hours(1)))
+            .build()
+            .unwrap();
+        assert!(client.get_cards().unwrap().is_empty());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["POST /oauth/token", "GET /content/mine"]
+        );
+        let token = client.token().unwrap();
+        assert_eq!(token.access_token(), "new");
+        assert!(!token.is_expired());
+        /* Refresh tokens are kept when not rotated */
+        assert_eq!(
+            serde_json::to_value(&token).unwrap()["refresh_token"],
+            "refresh"
+        );
+        assert!(matches!(
+            client.refresh_token(),
+            Ok(RefreshStatus::AlreadyValid)
+        ));
+    }
+
+    #[test]
+    fn retry_once_on_unauthorized() {
+        let (url, requests) = token_server(1);
+        let client = token_client(&url, token(TimeDelta::hours(1)))
+            .build()
+            .unwrap();
+        client.get_cards().unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "GET /content/mine",
+                "POST /oauth/token",
+                "GET /content/mine"
+            ]
+        );
+
+        /* A token rejected again is not refreshed a second time */
+        let (url, requests) = token_server(usize::MAX);
+        let client = token_client(&url, token(TimeDelta::hours(1)))
+            .build()
+            .unwrap();
+        assert!(matches!(client.get_cards(), Err(Error::AuthExpired)));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn refresh_callback_can_use_client() {
+        let (url, _) = token_server(0);
+        let cell: Arc<OnceLock<Arc<Client>>> = Arc::new(OnceLock::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = {
+            let (cell, seen) = (cell.clone(), seen.clone());
+            token_client(&url, token(-TimeDelta::hours(1)))
+                .token_store(Arc::new(ReadOnlyStore))
+                .on_token_refresh(move |token, error| {
+                    let current = cell.get().unwrap().token().unwrap();
+                    seen.lock().unwrap().push((
+                        token.access_token().to_string(),
+                        current.access_token().to_string(),
+                        error.map(ToString::to_string),
+                    ));
+                })
+                .build()
+                .unwrap()
+        };
+        let client = cell.get_or_init(|| Arc::new(client));
+        assert!(matches!(
+            client.refresh_token(),
+            Ok(RefreshStatus::Refreshed)
+        ));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                "new".to_string(),
+                "new".to_string(),
+                Some("token store error: read-only".to_string())
+            )]
+        );
+    }
+
+    fn login_server(errors: &'static [&'static str]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = {
+            let requests = requests.clone();
+            serve_with_status(move |_, path, body| {
+                let mut requests = requests.lock().unwrap();
+                requests.push(path.to_string());
+                let polls = requests.iter().filter(|p| *p == "/oauth/token").count();
+                match path {
+                    "/oauth/device/code" => {
+                        let login = json!({
+                            "device_code": "device",
+                            "user_code": "ABCD",
+                            "verification_uri": "https://login/activate",
+                            "verification_uri_complete": "https://login/activate?code=ABCD",
+                            "expires_in": 300,
+                            "interval": 5
+                        });
+                        (200, login.to_string().into_bytes())
+                    }
+                    _ if polls <= errors.len() => {
+                        assert!(String::from_utf8_lossy(body).contains("device_code=device"));
+                        let error = json!({ "error": errors[polls - 1] });
+                        (400, error.to_string().into_bytes())
+                    }
+                    _ => {
+                        let token = json!({ "access_token": "new", "expires_in": 3600 });
+                        (200, token.to_string().into_bytes())
+                    }
+                }
+            })
+        };
+        (url, requests)
+    }
+
+    fn login_client(url: &str) -> Client {
+        Client::builder("id")
+            .base_url(url)
+            .auth_url(format!("{}/oauth/device/code", url))
+            .token_url(format!("{}/oauth/token", url))
+            .token_store(Arc::new(MemoryStore::default()))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn device_login() {
+        let (url, _) = login_server(&["authorization_pending", "slow_down"]);
+        let client = login_client(&url);
+        let mut login = client.start_login().unwrap();
+        assert_eq!(login.user_code, "ABCD");
+        assert_eq!(login.interval, Duration::from_secs(5));
+
+        assert_eq!(client.poll_login(&mut login).unwrap(), LoginStatus::Pending);
+        assert_eq!(login.interval, Duration::from_secs(5));
+        assert_eq!(
+            client.poll_login(&mut login).unwrap(),
+            LoginStatus::SlowDown
+        );
+        assert_eq!(login.interval, Duration::from_secs(10));
+        assert!(client.token().is_none());
+        assert_eq!(
+            client.poll_login(&mut login).unwrap(),
+            LoginStatus::Complete
+        );
+        assert_eq!(client.token().unwrap().access_token(), "new");
+        let stored = client.store.as_ref().unwrap().load().unwrap();
+        assert_eq!(stored.unwrap().access_token(), "new");
+    }
+
+    #[test]
+    fn device_login_failures() {
+        let (url, _) = login_server(&["expired_token"]);
+        let client = login_client(&url);
+        let mut login = client.start_login().unwrap();
+        assert!(matches!(
+            client.poll_login(&mut login),
+            Err(Error::LoginExpired)
+        ));
+
+        let (url, _) = login_server(&["access_denied"]);
+        let client = login_client(&url);
+        let mut login = client.start_login().unwrap();
+        assert!(matches!(
+            client.poll_login(&mut login),
+            Err(Error::AccessDenied)
+        ));
+        assert!(client.token().is_none());
+
+        /* Expired logins are not polled */
+        let (url, requests) = login_server(&[]);
+        let client = login_client(&url);
+        let mut login = client.start_login().unwrap();
+        login.expires_at = Utc::now() - TimeDelta::seconds(1);
+        assert!(matches!(
+            client.poll_login(&mut login),
+            Err(Error::LoginExpired)
+        ));
+        assert_eq!(*requests.lock().unwrap(), vec!["/oauth/device/code"]);
+    }
 }
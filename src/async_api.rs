@@ -0,0 +1,593 @@
+//! Asynchronous client for the Yoto REST API, built on tokio.
+
+use reqwest::{header, header::HeaderMap, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::api::*;
+use crate::error::{Error, Result};
+use crate::model::*;
+use crate::store::TokenStore;
+
+/// Asynchronous counterpart of [`Client`], built with
+/// [`ClientBuilder::build_async`].
+pub struct AsyncClient {
+    pub id: String,
+    token: Mutex<Option<Token>>,
+    store: Option<Arc<dyn TokenStore>>,
+    on_token_refresh: Option<TokenCallback>,
+    config: Config,
+    client: reqwest::Client,
+}
+
+impl ClientBuilder {
+    /// Builds an [`AsyncClient`].
+    ///
+    /// The token is loaded from the token store synchronously; the client
+    /// then runs store operations on the blocking thread pool.
+    pub fn build_async(self) -> Result<AsyncClient> {
+        let mut builder = reqwest::Client::builder().user_agent(&self.config.user_agent);
+        if let Some(timeout) = self.config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let token = match (self.token, &self.store) {
+            (None, Some(store)) => store.load()?,
+            (token, _) => token,
+        };
+        Ok(AsyncClient {
+            id: self.id,
+            token: Mutex::new(token),
+            store: self.store,
+            on_token_refresh: self.on_token_refresh,
+            client: builder.build()?,
+            config: self.config,
+        })
+    }
+}
+
+impl AsyncClient {
+    /// Creates a client with the default configuration.
+    ///
+    /// Panics if the HTTP client cannot be initialized, like
+    /// [`reqwest::Client::new`]; use [`ClientBuilder::build_async`] to handle
+    /// that error instead.
+    pub fn new(client_id: &str, token: Option<Token>) -> AsyncClient {
+        Client::builder(client_id)
+            .token(token)
+            .build_async()
+            .expect("failed to initialize the HTTP client")
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns a copy of the current token, if authenticated.
+    pub async fn token(&self) -> Option<Token> {
+        self.token.lock().await.clone()
+    }
+
+    pub async fn set_token(&self, token: Option<Token>) {
+        *self.token.lock().await = token;
+    }
+
+    /// Clears the current token, both in memory and in the token store.
+    pub async fn logout(&self) -> Result<()> {
+        self.set_token(None).await;
+        self.with_store(|store| store.clear()).await
+    }
+
+    pub fn on_token_refresh<F>(&mut self, callback: F)
+    where
+        F: Fn(&Token, Option<&Error>) + Send + Sync + 'static,
+    {
+        self.on_token_refresh = Some(Box::new(callback));
+    }
+
+    /// Runs an operation on the token store, if any, on the blocking thread
+    /// pool since stores do file or keyring I/O.
+    async fn with_store<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Default + Send + 'static,
+        F: FnOnce(&dyn TokenStore) -> Result<T> + Send + 'static,
+    {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None => return Ok(T::default()),
+        };
+        tokio::task::spawn_blocking(move || operation(store.as_ref()))
+            .await
+            .map_err(|e| Error::Store(e.to_string()))?
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}{}", self.config.base_url, endpoint)
+    }
+
+    /// Starts a device code login.
+    pub async fn start_login(&self) -> Result<DeviceLogin> {
+        let data = login_form(&self.id, &self.config);
+        let response = self
+            .client
+            .post(&self.config.auth_url)
+            .form(&data)
+            .send()
+            .await?;
+        Ok(parse_response::<AuthResponse>(response).await?.into())
+    }
+
+    /// Polls the token endpoint once, without waiting.
+    pub async fn poll_login(&self, login: &mut DeviceLogin) -> Result<LoginStatus> {
+        if login.is_expired() {
+            return Err(Error::LoginExpired);
+        }
+        let result = self
+            .request_token(GrantType::DeviceCode(login.device_code()))
+            .await;
+        match login_status(login, result)? {
+            (status, Some(token)) => {
+                let saved = token.clone();
+                self.with_store(move |store| store.save(&saved)).await?;
+                self.set_token(Some(token)).await;
+                Ok(status)
+            }
+            (status, None) => Ok(status),
+        }
+    }
+
+    /// Polls the login at the server interval until it completes or fails.
+    ///
+    /// `on_poll` is called after every poll; returning `false` cancels the
+    /// login.
+    pub async fn wait_login<F>(&self, login: &mut DeviceLogin, mut on_poll: F) -> Result<()>
+    where
+        F: FnMut(&DeviceLogin, LoginStatus) -> bool,
+    {
+        loop {
+            let status = self.poll_login(login).await?;
+            if !on_poll(login, status) {
+                return Err(Error::LoginCancelled);
+            }
+            if status == LoginStatus::Complete {
+                return Ok(());
+            }
+            sleep(login.interval).await;
+        }
+    }
+
+    /// Refreshes the token if it is about to expire.
+    pub async fn refresh_token(&self) -> Result<RefreshStatus> {
+        let previous = self.token().await.ok_or(Error::NotAuthenticated)?;
+        if self.access_token(None).await? == previous.access_token() {
+            Ok(RefreshStatus::AlreadyValid)
+        } else {
+            Ok(RefreshStatus::Refreshed)
+        }
+    }
+
+    /// Saves a refreshed token to the store and notifies the refresh
+    /// callback, which may call the client again.
+    ///
+    /// A store failure must not fail the request that triggered the refresh,
+    /// so it is only reported to the callback.
+    async fn token_refreshed(&self, token: &Token) {
+        let saved = token.clone();
+        let saved = self.with_store(move |store| store.save(&saved)).await;
+        if let Some(callback) = &self.on_token_refresh {
+            callback(token, saved.as_ref().err());
+        }
+    }
+
+    async fn request_token(
+        &self,
+        grant_type: GrantType<'_>,
+    ) -> std::result::Result<Token, AuthError> {
+        let data = token_form(&self.id, &self.config, grant_type);
+        let response = self
+            .client
+            .post(&self.config.token_url)
+            .form(&data)
+            .send()
+            .await
+            .map_err(|e| AuthError::Failed(e.into()))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| AuthError::Failed(e.into()))?;
+        parse_token(status, &headers, body)
+    }
+
+    /// Returns a valid access token, renewing it first if it is about to
+    /// expire or if the server rejected it.
+    async fn access_token(&self, rejected: Option<&str>) -> Result<String> {
+        let token = {
+            /* Held during the refresh, so that concurrent requests wait for
+             * it rather than refreshing again */
+            let mut slot = self.token.lock().await;
+            let refresh_token = match token_action(slot.as_ref(), rejected)? {
+                TokenAction::Use(access_token) => return Ok(access_token),
+                TokenAction::Refresh(refresh_token) => refresh_token,
+            };
+            let result = self
+                .request_token(GrantType::RefreshToken(&refresh_token))
+                .await;
+            let token = refreshed_token(&refresh_token, result)?;
+            *slot = Some(token.clone());
+            token
+        };
+        self.token_refreshed(&token).await;
+        Ok(token.access_token().to_string())
+    }
+
+    /// Sends an authenticated request, retrying once with a refreshed token
+    /// if the server rejects the current one.
+    async fn send_authorized<F>(&self, request: F) -> Result<Response>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        let access_token = self.access_token(None).await?;
+        let response = request(&access_token).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let access_token = self.access_token(Some(&access_token)).await?;
+        Ok(request(&access_token).send().await?)
+    }
+
+    pub async fn get_objects<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T> {
+        self.get_object(endpoint, None).await
+    }
+
+    pub async fn get_object<T: DeserializeOwned>(
+        &self,
+        endpoint: impl AsRef<str>,
+        params: Option<&HashMap<&str, &str>>,
+    ) -> Result<T> {
+        let url = self.url(endpoint.as_ref());
+        let response = self
+            .send_authorized(|token| {
+                let builder = self.client.get(&url).bearer_auth(token);
+                match params {
+                    Some(p) => builder.query(p),
+                    None => builder,
+                }
+            })
+            .await?;
+        parse_response(response).await
+    }
+
+    pub async fn post_object<T: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        endpoint: impl AsRef<str>,
+        body: &T,
+    ) -> Result<R> {
+        let url = self.url(endpoint.as_ref());
+        let response = self
+            .send_authorized(|token| self.client.post(&url).bearer_auth(token).json(body))
+            .await?;
+        parse_response(response).await
+    }
+
+    pub async fn delete_object(&self, endpoint: impl AsRef<str>) -> Result<()> {
+        let url = self.url(endpoint.as_ref());
+        let response = self
+            .send_authorized(|token| self.client.delete(&url).bearer_auth(token))
+            .await?;
+        check_response(response).await.map(|_| ())
+    }
+
+    pub async fn get_devices(&self) -> Result<Vec<Device>> {
+        Ok(self
+            .get_objects::<DeviceList>(endpoint::DEVICES)
+            .await?
+            .devices)
+    }
+
+    pub async fn get_device_status(&self, id: &str) -> Result<DeviceStatus> {
+        self.get_object::<DeviceStatus>(endpoint::device_status(id), None)
+            .await
+    }
+
+    pub async fn get_device_config(&self, id: &str) -> Result<DeviceConfig> {
+        Ok(self
+            .get_object::<DeviceConfigResponse>(endpoint::device_config(id), None)
+            .await?
+            .device
+            .config)
+    }
+
+    pub async fn update_device_config(&self, id: &str, config: &DeviceConfig) -> Result<()> {
+        let url = self.url(&endpoint::device_config(id));
+        let body = DeviceConfigUpdate {
+            device_id: id,
+            config,
+        };
+        let response = self
+            .send_authorized(|token| self.client.post(&url).bearer_auth(token).json(&body))
+            .await?;
+        check_response(response).await.map(|_| ())
+    }
+
+    pub async fn get_cards(&self) -> Result<Vec<Card>> {
+        Ok(self.get_objects::<CardList>(endpoint::CARDS).await?.cards)
+    }
+
+    pub async fn get_card(&self, id: &str, playable: bool) -> Result<Card> {
+        let params = card_params(playable);
+        Ok(self
+            .get_object::<ContentResponse>(endpoint::card(id), Some(&params))
+            .await?
+            .card)
+    }
+
+    /// Creates a card, or updates it if its ID is set, returning the card as
+    /// stored by the server.
+    pub async fn save_card(&self, card: &Card) -> Result<Card> {
+        Ok(self
+            .post_object::<_, ContentResponse>(endpoint::CONTENT, card)
+            .await?
+            .card)
+    }
+
+    /// Creates a new card, ignoring its ID if set, and returns it with the ID
+    /// assigned by the server.
+    pub async fn create_card(&self, card: &Card) -> Result<Card> {
+        Ok(self
+            .post_object::<_, ContentResponse>(endpoint::CONTENT, &new_card(card)?)
+            .await?
+            .card)
+    }
+
+    /// Replaces the content of an existing card.
+    pub async fn update_card(&self, card: &Card) -> Result<Card> {
+        check_card_id(card)?;
+        self.save_card(card).await
+    }
+
+    pub async fn delete_card(&self, id: &str) -> Result<()> {
+        self.delete_object(endpoint::card(id)).await
+    }
+
+    pub async fn get_family_images(&self) -> Result<Vec<Image>> {
+        Ok(self
+            .get_objects::<ImageList>(endpoint::FAMILY_IMAGES)
+            .await?
+            .images)
+    }
+
+    pub async fn get_display_icons(&self) -> Result<Vec<DisplayIcon>> {
+        Ok(self
+            .get_objects::<IconList>(endpoint::DISPLAY_ICONS)
+            .await?
+            .icons)
+    }
+
+    /// Lists the display icons provided by Yoto to all users.
+    pub async fn get_public_display_icons(&self) -> Result<Vec<DisplayIcon>> {
+        Ok(self
+            .get_objects::<IconList>(endpoint::PUBLIC_DISPLAY_ICONS)
+            .await?
+            .icons)
+    }
+
+    async fn request_audio_upload_url(&self) -> Result<Upload> {
+        Ok(self
+            .get_objects::<UploadResponse>(endpoint::AUDIO_UPLOAD_URL)
+            .await?
+            .upload)
+    }
+
+    async fn send_audio_file(&self, path: &Path, upload: &Upload) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, audio_content_type(path)?);
+
+        let data: Vec<u8> = tokio::fs::read(path).await?;
+        let response = self
+            .client
+            .put(&upload.url)
+            .headers(headers)
+            .body(data)
+            .send()
+            .await?;
+        check_response(response).await.map(|_| ())
+    }
+
+    async fn wait_audio_transcode(&self, upload: &Upload) -> Result<Transcoded> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            header::HeaderValue::from_static("application/json"),
+        );
+
+        let mut attempts = 0;
+        let url = self.url(&endpoint::transcoded(&upload.id));
+
+        loop {
+            let response = self
+                .send_authorized(|token| {
+                    self.client
+                        .get(&url)
+                        .bearer_auth(token)
+                        .headers(headers.clone())
+                })
+                .await?;
+            let audio = parse_response::<TranscodeResponse>(response)
+                .await?
+                .transcode;
+            if let Some(sha256) = audio.uri {
+                return Ok(Transcoded {
+                    sha256,
+                    info: audio.info.unwrap_or_default(),
+                });
+            }
+            attempts += 1;
+            if attempts >= TRANSCODE_ATTEMPTS {
+                return Err(Error::TranscodeTimeout);
+            }
+            sleep(TRANSCODE_INTERVAL).await;
+        }
+    }
+
+    /// Uploads an audio file and waits for it to be transcoded.
+    pub async fn upload_audio(&self, path: &Path) -> Result<Transcoded> {
+        let upload = self.request_audio_upload_url().await?;
+        self.send_audio_file(path, &upload).await?;
+        self.wait_audio_transcode(&upload).await
+    }
+
+    /// Uploads an audio file, returning the SHA-256 of the transcoded audio.
+    pub async fn upload_audio_file(&self, path: &Path) -> Result<String> {
+        Ok(self.upload_audio(path).await?.sha256)
+    }
+
+    /// Uploads a custom display icon, returning its media ID.
+    pub async fn upload_display_icon(&self, data: Vec<u8>, filename: &str) -> Result<String> {
+        let url = self.url(endpoint::DISPLAY_ICON_UPLOAD);
+        let params = icon_upload_params(filename);
+        let response = self
+            .send_authorized(|token| {
+                self.client
+                    .post(&url)
+                    .bearer_auth(token)
+                    .query(&params)
+                    .body(data.clone())
+            })
+            .await?;
+        Ok(parse_response::<IconUploadResponse>(response)
+            .await?
+            .icon
+            .media_id)
+    }
+
+    /// Downloads a signed media URL, as found in playable cards.
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let response = check_response(self.client.get(url).send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+async fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        Err(status_error(status, &headers, body))
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let body = check_response(response).await?.text().await?;
+    Ok(serde_json::from_str::<T>(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{token, token_client, token_server, ReadOnlyStore};
+    use chrono::TimeDelta;
+    use std::sync::{Mutex, OnceLock};
+
+    #[tokio::test]
+    async fn refresh_before_request() {
+        let (url, requests) = token_server(0);
+        let client = token_client(&url, token(-TimeDelta::hours(1)))
+            .build_async()
+            .unwrap();
+        assert!(client.get_cards().await.unwrap().is_empty());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["POST /oauth/token", "GET /content/mine"]
+        );
+        let token = client.token().await.unwrap();
+        assert_eq!(token.access_token(), "new");
+        assert!(!token.is_expired());
+        /* Refresh tokens are kept when not rotated */
+        assert_eq!(
+            serde_json::to_value(&token).unwrap()["refresh_token"],
+            "refresh"
+        );
+        assert!(matches!(
+            client.refresh_token().await,
+            Ok(RefreshStatus::AlreadyValid)
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_once_on_unauthorized() {
+        let (url, requests) = token_server(1);
+        let client = token_client(&url, token(TimeDelta::hours(1)))
+            .build_async()
+            .unwrap();
+        client.get_cards().await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "GET /content/mine",
+                "POST /oauth/token",
+                "GET /content/mine"
+            ]
+        );
+
+        /* A token rejected again is not refreshed a second time */
+        let (url, requests) = token_server(usize::MAX);
+        let client = token_client(&url, token(TimeDelta::hours(1)))
+            .build_async()
+            .unwrap();
+        assert!(matches!(client.get_cards().await, Err(Error::AuthExpired)));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refresh_callback_can_use_client() {
+        let (url, _) = token_server(0);
+        let cell: Arc<OnceLock<Arc<AsyncClient>>> = Arc::new(OnceLock::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = {
+            let (cell, seen) = (cell.clone(), seen.clone());
+            token_client(&url, token(-TimeDelta::hours(1)))
+                .token_store(Arc::new(ReadOnlyStore))
+                .on_token_refresh(move |token, error| {
+                    /* The callback is synchronous: it cannot await the lock */
+                    let current = cell
+                        .get()
+                        .unwrap()
+                        .token
+                        .try_lock()
+                        .unwrap()
+                        .clone()
+                        .unwrap();
+                    seen.lock().unwrap().push((
+                        token.access_token().to_string(),
+                        current.access_token().to_string(),
+                        error.map(ToString::to_string),
+                    ));
+                })
+                .build_async()
+                .unwrap()
+        };
+        let client = cell.get_or_init(|| Arc::new(client));
+        assert!(matches!(
+            client.refresh_token().await,
+            Ok(RefreshStatus::Refreshed)
+        ));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                "new".to_string(),
+                "new".to_string(),
+                Some("token store error: read-only".to_string())
+            )]
+        );
+    }
+}
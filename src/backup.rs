@@ -0,0 +1,774 @@
+//! Backup of cards, with their audio and icons, to a directory or a zip
+//! archive, and restore of these backups.
+//!
+//! A backup is laid out as:
+//!
+//! ```text
+//! <card title> (<card id>)/
+//!     card.json
+//!     01 - <chapter title>/
+//!         icon.png
+//!         01 - <track title>.mp3
+//!         01 - <track title>.icon.png
+//! ```
+//!
+//! Chapters and tracks are numbered by their position in the card, so that
+//! backups of the same card are always laid out identically.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::api::Client;
+use crate::error::{Error, Result};
+use crate::model::{media_url, Card, Chapter, Icon, MediaType, Track};
+
+pub const CARD_FILE: &str = "card.json";
+
+/// Maximum length of a title once turned into a file name.
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Audio,
+    Icon,
+}
+
+/// Media file of a card, with its path relative to the backup root.
+///
+/// Icons have no extension, it is determined once downloaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaFile {
+    pub path: String,
+    pub url: String,
+    pub kind: MediaKind,
+}
+
+/// Outcome of a backup.
+#[derive(Debug, Default)]
+pub struct Backup {
+    /// Directory or zip archive created.
+    pub path: PathBuf,
+    /// Files written, relative to the backup root.
+    pub files: Vec<String>,
+    /// Media that could not be downloaded, e.g. because the card was not
+    /// fetched as playable.
+    pub skipped: Vec<MediaFile>,
+}
+
+enum Target {
+    Directory(PathBuf),
+    Zip(ZipWriter<File>),
+}
+
+/// Turns a title into a file name valid on all platforms.
+pub fn sanitize(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim().trim_matches('.').trim();
+    if name.is_empty() {
+        "untitled".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Name of the backup directory or archive (without extension) of a card.
+pub fn root_name(card: &Card) -> String {
+    format!("{} ({})", sanitize(&card.title), sanitize(&card.card_id))
+}
+
+fn chapter_dir(index: usize, chapter: &Chapter) -> String {
+    format!("{:02} - {}", index + 1, sanitize(&chapter.title))
+}
+
+fn track_stem(dir: &str, index: usize, track: &Track) -> String {
+    format!("{}/{:02} - {}", dir, index + 1, sanitize(&track.title))
+}
+
+fn audio_path(stem: &str, track: &Track) -> String {
+    format!("{}.{}", stem, track.format.extension())
+}
+
+fn icon_url(icon: &Option<Icon>) -> Option<&String> {
+    icon.as_ref().and_then(|icon| icon.small.as_ref())
+}
+
+/// Lists the media files of a card, in card order.
+///
+/// Streams are not part of backups, only their URL is.
+pub fn media_files(card: &Card) -> Vec<MediaFile> {
+    let mut files = Vec::new();
+    for (i, chapter) in card.content.chapters.iter().enumerate() {
+        let dir = chapter_dir(i, chapter);
+        if let Some(url) = icon_url(&chapter.display) {
+            files.push(MediaFile {
+                path: format!("{}/icon", dir),
+                url: url.clone(),
+                kind: MediaKind::Icon,
+            });
+        }
+        for (j, track) in chapter.tracks.iter().enumerate() {
+            let stem = track_stem(&dir, j, track);
+            if matches!(track.media, MediaType::Audio) {
+                files.push(MediaFile {
+                    path: audio_path(&stem, track),
+                    url: track.track_url.clone(),
+                    kind: MediaKind::Audio,
+                });
+            }
+            if let Some(url) = icon_url(&track.icon) {
+                files.push(MediaFile {
+                    path: format!("{}.icon", stem),
+                    url: url.clone(),
+                    kind: MediaKind::Icon,
+                });
+            }
+        }
+    }
+    files
+}
+
+/// Guesses the extension of an image from its first bytes.
+fn image_extension(data: &[u8]) -> &'static str {
+    if data.starts_with(b"GIF8") {
+        "gif"
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "jpg"
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "webp"
+    } else {
+        "png"
+    }
+}
+
+impl Target {
+    fn create(path: &Path, zip: bool) -> Result<Target> {
+        if zip {
+            Ok(Target::Zip(ZipWriter::new(File::create(path)?)))
+        } else {
+            fs::create_dir_all(path)?;
+            Ok(Target::Directory(path.to_path_buf()))
+        }
+    }
+
+    fn write(&mut self, name: &str, data: &[u8], compress: bool) -> Result<()> {
+        match self {
+            Target::Directory(root) => {
+                let path = root.join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, data)?;
+            }
+            Target::Zip(zip) => {
+                let method = if compress {
+                    CompressionMethod::Deflated
+                } else {
+                    CompressionMethod::Stored
+                };
+                zip.start_file(name, FileOptions::default().compression_method(method))
+                    .map_err(zip_error)?;
+                zip.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Target::Zip(mut zip) = self {
+            zip.finish().map_err(zip_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn zip_error(err: zip::result::ZipError) -> Error {
+    Error::Io(err.into())
+}
+
+/// Backs a card up, with its audio and icons, under `dest`.
+///
+/// The stored JSON keeps the media references of the card, while the media
+/// are downloaded from the playable version of it. With `zip`, the backup is
+/// packed into `<root name>.zip` instead of a directory.
+pub fn backup_card(client: &Client, id: &str, dest: &Path, zip: bool) -> Result<Backup> {
+    let card = client.get_card(id, false)?;
+    backup(client, &card, dest, zip)
+}
+
+/// Backs up a card already fetched (not as playable), see [`backup_card`].
+pub fn backup(client: &Client, card: &Card, dest: &Path, zip: bool) -> Result<Backup> {
+    let playable = client.get_card(&card.card_id, true)?;
+    let path = if zip {
+        dest.join(format!("{}.zip", root_name(card)))
+    } else {
+        dest.join(root_name(card))
+    };
+    fs::create_dir_all(dest)?;
+
+    let mut backup = Backup {
+        path,
+        ..Backup::default()
+    };
+    let prefix = if zip {
+        format!("{}/", root_name(card))
+    } else {
+        String::new()
+    };
+    let mut target = Target::create(&backup.path, zip)?;
+
+    let json = serde_json::to_vec_pretty(card)?;
+    target.write(&format!("{}{}", prefix, CARD_FILE), &json, true)?;
+    backup.files.push(CARD_FILE.to_string());
+
+    /* Chapters and tracks often share the same icon */
+    let mut downloaded: HashMap<String, Vec<u8>> = HashMap::new();
+    for file in media_files(&playable) {
+        if !file.url.starts_with("https://") && !file.url.starts_with("http://") {
+            backup.skipped.push(file);
+            continue;
+        }
+        let data = match downloaded.get(&file.url) {
+            Some(data) => data.clone(),
+            None => client.download(&file.url)?,
+        };
+        let name = match file.kind {
+            MediaKind::Audio => file.path,
+            MediaKind::Icon => format!("{}.{}", file.path, image_extension(&data)),
+        };
+        target.write(&format!("{}{}", prefix, name), &data, false)?;
+        backup.files.push(name);
+        if file.kind == MediaKind::Icon {
+            downloaded.insert(file.url, data);
+        }
+    }
+
+    target.finish()?;
+    Ok(backup)
+}
+
+/// Outcome of a restore.
+///
+/// Media are named by their [`MediaFile::path`] in the backup, so icons have
+/// no extension.
+#[derive(Debug, Default)]
+pub struct Restore {
+    /// Card as saved by the server.
+    pub card: Card,
+    /// Media uploaded again from the backup.
+    pub uploaded: Vec<String>,
+    /// Media whose reference was kept.
+    pub reused: Vec<String>,
+}
+
+enum Source {
+    Directory(PathBuf),
+    Zip {
+        archive: ZipArchive<File>,
+        prefix: String,
+    },
+}
+
+impl Source {
+    fn open(path: &Path) -> Result<Source> {
+        if path.is_dir() {
+            return Ok(Source::Directory(path.to_path_buf()));
+        }
+        let archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+        let prefix = archive
+            .file_names()
+            .filter_map(|name| name.strip_suffix(CARD_FILE))
+            .filter(|prefix| prefix.is_empty() || prefix.ends_with('/'))
+            .min_by_key(|prefix| prefix.len())
+            .ok_or_else(|| Error::InvalidCard(format!("no {} in {}", CARD_FILE, path.display())))?
+            .to_string();
+        Ok(Source::Zip { archive, prefix })
+    }
+
+    fn contains(&mut self, name: &str) -> bool {
+        match self {
+            Source::Directory(root) => root.join(name).is_file(),
+            Source::Zip { archive, prefix } => {
+                archive.by_name(&format!("{}{}", prefix, name)).is_ok()
+            }
+        }
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        match self {
+            Source::Directory(root) => Ok(fs::read(root.join(name))?),
+            Source::Zip { archive, prefix } => {
+                let mut file = archive
+                    .by_name(&format!("{}{}", prefix, name))
+                    .map_err(zip_error)?;
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Uploads an audio file, extracting it first if needed.
+    fn upload_audio(&mut self, client: &Client, name: &str) -> Result<String> {
+        if let Source::Directory(root) = self {
+            return client.upload_audio_file(&root.join(name));
+        }
+        let data = self.read(name)?;
+        /* The upload content type is derived from the extension */
+        let extension = Path::new(name)
+            .extension()
+            .map(|ext| format!(".{}", ext.to_string_lossy()))
+            .unwrap_or_default();
+        let mut file = tempfile::Builder::new().suffix(&extension).tempfile()?;
+        file.write_all(&data)?;
+        client.upload_audio_file(file.path())
+    }
+
+    fn find_icon(&mut self, stem: &str) -> Option<String> {
+        ICON_EXTENSIONS
+            .iter()
+            .map(|ext| format!("{}.{}", stem, ext))
+            .find(|name| self.contains(name))
+    }
+}
+
+const ICON_EXTENSIONS: [&str; 4] = ["png", "gif", "jpg", "webp"];
+
+/// Restores the icon of a chapter or track, uploading it again unless it is
+/// one of the account or public icons, or no copy of it was backed up.
+fn restore_icon(
+    client: &Client,
+    source: &mut Source,
+    icon: &mut Option<Icon>,
+    stem: &str,
+    known: &mut HashMap<String, String>,
+    restore: &mut Restore,
+) -> Result<()> {
+    let Some(reference) = icon.as_mut().and_then(|icon| icon.small.as_mut()) else {
+        return Ok(());
+    };
+    if let Some(new) = known.get(reference.as_str()) {
+        /* Icons shared with an icon uploaded earlier are reported once */
+        if new == reference {
+            restore.reused.push(stem.to_string());
+        }
+        *reference = new.clone();
+        return Ok(());
+    }
+    match source.find_icon(stem) {
+        Some(name) => {
+            let data = source.read(&name)?;
+            let filename = name.rsplit('/').next().unwrap_or_default();
+            let media_id = client.upload_display_icon(data, filename)?;
+            let new = media_url(&media_id);
+            known.insert(reference.clone(), new.clone());
+            *reference = new;
+            restore.uploaded.push(stem.to_string());
+        }
+        None => {
+            known.insert(reference.clone(), reference.clone());
+            restore.reused.push(stem.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Restores a card from a backup directory or zip archive.
+///
+/// Audio already referenced as transcoded media is reused, unless `reupload`
+/// is set; other audio is uploaded again from the backup. The card is created
+/// anew, or overwrites the card `id` if given.
+pub fn restore_card(
+    client: &Client,
+    path: &Path,
+    id: Option<&str>,
+    reupload: bool,
+) -> Result<Restore> {
+    let mut source = Source::open(path)?;
+    let mut card: Card = serde_json::from_slice(&source.read(CARD_FILE)?)?;
+    let mut restore = Restore::default();
+
+    /* Icons of the account and public icons can be referenced as they are */
+    let mut known: HashMap<String, String> = client
+        .get_display_icons()?
+        .into_iter()
+        .chain(client.get_public_display_icons()?)
+        .map(|icon| {
+            let reference = media_url(&icon.media_id);
+            (reference.clone(), reference)
+        })
+        .collect();
+
+    for (i, chapter) in card.content.chapters.iter_mut().enumerate() {
+        let dir = chapter_dir(i, chapter);
+        restore_icon(
+            client,
+            &mut source,
+            &mut chapter.display,
+            &format!("{}/icon", dir),
+            &mut known,
+            &mut restore,
+        )?;
+        for (j, track) in chapter.tracks.iter_mut().enumerate() {
+            let stem = track_stem(&dir, j, track);
+            let audio = audio_path(&stem, track);
+            let transcoded = track.track_url.starts_with("yoto:#");
+            if matches!(track.media, MediaType::Audio)
+                && (reupload || !transcoded)
+                && source.contains(&audio)
+            {
+                let sha = source.upload_audio(client, &audio)?;
+                track.track_url = media_url(&sha);
+                restore.uploaded.push(audio);
+            } else {
+                restore.reused.push(audio);
+            }
+            restore_icon(
+                client,
+                &mut source,
+                &mut track.icon,
+                &format!("{}.icon", stem),
+                &mut known,
+                &mut restore,
+            )?;
+        }
+    }
+
+    card.card_id = id.unwrap_or_default().to_string();
+    card.clear_timestamps();
+    restore.card = client.save_card(&card)?;
+    Ok(restore)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client, serve};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn layout() {
+        let card: Card = serde_json::from_value(json!({
+            "cardId": "5WsQ9",
+            "title": "Bed/time: stories?",
+            "content": {
+                "chapters": [{
+                    "key": "01",
+                    "title": "The Fox ",
+                    "display": { "icon16x16": "https://media/fox.png" },
+                    "tracks": [{
+                        "key": "01", "title": "Part <1>", "trackUrl": "https://media/1",
+                        "type": "audio", "format": "aac", "overlayLabel": "1",
+                        "duration": 10, "fileSize": 100,
+                        "display": { "icon16x16": "https://media/fox.png" }
+                    }, {
+                        "key": "02", "title": "...", "trackUrl": "yoto:#abc",
+                        "type": "audio", "format": "mp3", "overlayLabel": "2",
+                        "duration": 10, "fileSize": 100
+                    }]
+                }]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(root_name(&card), "Bed_time_ stories_ (5WsQ9)");
+        let paths: Vec<_> = media_files(&card)
+            .into_iter()
+            .map(|file| (file.path, file.kind))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("01 - The Fox/icon".to_string(), MediaKind::Icon),
+                (
+                    "01 - The Fox/01 - Part _1_.aac".to_string(),
+                    MediaKind::Audio
+                ),
+                (
+                    "01 - The Fox/01 - Part _1_.icon".to_string(),
+                    MediaKind::Icon
+                ),
+                (
+                    "01 - The Fox/02 - untitled.mp3".to_string(),
+                    MediaKind::Audio
+                ),
+            ]
+        );
+    }
+
+    /// Lists the files under `dir`, relative to `root`.
+    fn list_files(root: &Path, dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(list_files(root, &path));
+            } else {
+                let name = path.strip_prefix(root).unwrap().to_str().unwrap();
+                files.push(name.replace('\\', "/"));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn backup_media() {
+        let url = Arc::new(Mutex::new(String::new()));
+        let card = {
+            let url = url.clone();
+            move |playable: bool| {
+                let media = |id: &str, kind: &str| {
+                    if playable {
+                        format!("{}/{}/{}", url.lock().unwrap(), kind, id)
+                    } else {
+                        media_url(id)
+                    }
+                };
+                json!({
+                    "cardId": "5WsQ9",
+                    "title": "Stories",
+                    "content": {
+                        "chapters": [{
+                            "key": "01",
+                            "title": "Fox",
+                            "display": { "icon16x16": media("gif", "icons") },
+                            "tracks": [{
+                                "key": "01", "title": "One", "trackUrl": media("sha1", "audio"),
+                                "type": "audio", "format": "mp3", "overlayLabel": "1",
+                                "duration": 10, "fileSize": 100,
+                                "display": { "icon16x16": media("jpg", "icons") }
+                            }, {
+                                "key": "02", "title": "Two", "trackUrl": media("sha2", "audio"),
+                                "type": "audio", "format": "aac", "overlayLabel": "2",
+                                "duration": 10, "fileSize": 100,
+                                "display": { "icon16x16": media("webp", "icons") }
+                            }]
+                        }, {
+                            "key": "02",
+                            "title": "Owl",
+                            "display": { "icon16x16": media("png", "icons") },
+                            "tracks": [{
+                                "key": "03", "title": "Three", "trackUrl": "yoto:#sha3",
+                                "type": "audio", "format": "mp3", "overlayLabel": "3",
+                                "duration": 10, "fileSize": 100,
+                                "display": { "icon16x16": media("gif", "icons") }
+                            }]
+                        }]
+                    }
+                })
+            }
+        };
+        let downloads = Arc::new(Mutex::new(Vec::new()));
+        *url.lock().unwrap() = {
+            let downloads = downloads.clone();
+            serve(move |method, path, _| {
+                if path.starts_with("/content/5WsQ9") {
+                    let card = card(path.contains("playable=true"));
+                    return json!({ "card": card }).to_string().into_bytes();
+                }
+                downloads.lock().unwrap().push(path.to_string());
+                match (method, path) {
+                    ("GET", "/icons/gif") => b"GIF89a".to_vec(),
+                    ("GET", "/icons/jpg") => vec![0xff, 0xd8, 0xff, 0xe0],
+                    ("GET", "/icons/webp") => b"RIFF\x04\0\0\0WEBPVP8 ".to_vec(),
+                    ("GET", "/icons/png") => b"\x89PNG".to_vec(),
+                    ("GET", _) if path.starts_with("/audio/") => path.as_bytes().to_vec(),
+                    _ => panic!("unexpected request {} {}", method, path),
+                }
+            })
+        };
+        let client = client(&url.lock().unwrap());
+        let expected = [
+            ("01 - Fox/icon.gif", b"GIF89a".to_vec()),
+            ("01 - Fox/01 - One.mp3", b"/audio/sha1".to_vec()),
+            ("01 - Fox/01 - One.icon.jpg", vec![0xff, 0xd8, 0xff, 0xe0]),
+            ("01 - Fox/02 - Two.aac", b"/audio/sha2".to_vec()),
+            (
+                "01 - Fox/02 - Two.icon.webp",
+                b"RIFF\x04\0\0\0WEBPVP8 ".to_vec(),
+            ),
+            ("02 - Owl/icon.png", b"\x89PNG".to_vec()),
+            ("02 - Owl/01 - Three.icon.gif", b"GIF89a".to_vec()),
+        ];
+        let mut names = vec![CARD_FILE];
+        names.extend(expected.iter().map(|(name, _)| *name));
+
+        let dir = tempfile::tempdir().unwrap();
+        for zip in [false, true] {
+            let backup = backup_card(&client, "5WsQ9", dir.path(), zip).unwrap();
+            assert_eq!(backup.files, names);
+            assert_eq!(
+                backup.skipped,
+                vec![MediaFile {
+                    path: "02 - Owl/01 - Three.mp3".to_string(),
+                    url: "yoto:#sha3".to_string(),
+                    kind: MediaKind::Audio,
+                }]
+            );
+
+            let mut written = if zip {
+                assert_eq!(backup.path, dir.path().join("Stories (5WsQ9).zip"));
+                let archive = ZipArchive::new(File::open(&backup.path).unwrap()).unwrap();
+                archive.file_names().map(str::to_string).collect()
+            } else {
+                assert_eq!(backup.path, dir.path().join("Stories (5WsQ9)"));
+                list_files(dir.path(), &backup.path)
+            };
+            written.sort();
+            let mut entries: Vec<_> = names
+                .iter()
+                .map(|name| format!("Stories (5WsQ9)/{}", name))
+                .collect();
+            entries.sort();
+            assert_eq!(written, entries);
+
+            let mut source = Source::open(&backup.path).unwrap();
+            let json: Value = serde_json::from_slice(&source.read(CARD_FILE).unwrap()).unwrap();
+            assert_eq!(
+                json["content"]["chapters"][0]["tracks"][0]["trackUrl"],
+                "yoto:#sha1"
+            );
+            for (name, data) in &expected {
+                assert_eq!(source.read(name).unwrap(), *data, "{}", name);
+            }
+        }
+        /* Icons shared by several chapters or tracks are downloaded once */
+        assert_eq!(
+            downloads
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|path| *path == "/icons/gif")
+                .count(),
+            2
+        );
+    }
+
+    fn display_icon(media_id: &str) -> Value {
+        json!({
+            "displayIconId": media_id,
+            "mediaId": media_id,
+            "public": false,
+            "url": format!("https://media/{}", media_id),
+            "createdAt": "2024-01-01T00:00:00Z",
+            "userId": "user"
+        })
+    }
+
+    #[test]
+    fn restore_icons() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let card = json!({
+            "cardId": "5WsQ9",
+            "title": "Stories",
+            "createdAt": "2024-01-01T00:00:00Z",
+            "updatedAt": "2024-02-01T00:00:00Z",
+            "content": {
+                "chapters": [{
+                    "key": "01",
+                    "title": "Fox",
+                    "display": { "icon16x16": "yoto:#public" },
+                    "tracks": [{
+                        "key": "01", "title": "One", "trackUrl": "yoto:#sha1",
+                        "type": "audio", "format": "mp3", "overlayLabel": "1",
+                        "duration": 10, "fileSize": 100,
+                        "display": { "icon16x16": "yoto:#mine" }
+                    }, {
+                        "key": "02", "title": "Two", "trackUrl": "yoto:#sha2",
+                        "type": "audio", "format": "mp3", "overlayLabel": "2",
+                        "duration": 10, "fileSize": 100,
+                        "display": { "icon16x16": "yoto:#deleted" }
+                    }]
+                }]
+            }
+        });
+        fs::write(root.join(CARD_FILE), card.to_string()).unwrap();
+        fs::create_dir(root.join("01 - Fox")).unwrap();
+        for icon in ["icon.png", "01 - One.icon.png", "02 - Two.icon.png"] {
+            fs::write(root.join("01 - Fox").join(icon), b"PNG").unwrap();
+        }
+
+        let saved = Arc::new(Mutex::new(Value::Null));
+        let url = {
+            let saved = saved.clone();
+            serve(move |method, path, body| {
+                let response = match (method, path.split('?').next().unwrap()) {
+                    ("GET", "/media/displayIcons/user/me") => {
+                        json!({ "displayIcons": [display_icon("mine")] })
+                    }
+                    ("GET", "/media/displayIcons/user/yoto") => {
+                        json!({ "displayIcons": [display_icon("public")] })
+                    }
+                    ("POST", "/media/displayIcons/user/me/upload") => {
+                        json!({ "displayIcon": { "mediaId": "uploaded" } })
+                    }
+                    ("POST", "/content") => {
+                        let card: Value = serde_json::from_slice(body).unwrap();
+                        *saved.lock().unwrap() = card.clone();
+                        json!({ "card": card })
+                    }
+                    _ => panic!("unexpected request {} {}", method, path),
+                };
+                response.to_string().into_bytes()
+            })
+        };
+
+        let restore = restore_card(&client(&url), root, None, false).unwrap();
+        assert_eq!(restore.uploaded, vec!["01 - Fox/02 - Two.icon"]);
+        assert_eq!(
+            restore.reused,
+            vec![
+                "01 - Fox/icon",
+                "01 - Fox/01 - One.mp3",
+                "01 - Fox/01 - One.icon",
+                "01 - Fox/02 - Two.mp3",
+            ]
+        );
+        let saved = saved.lock().unwrap();
+        for field in ["cardId", "createdAt", "updatedAt"] {
+            assert!(saved.get(field).is_none(), "{}", field);
+        }
+        let chapter = &saved["content"]["chapters"][0];
+        assert_eq!(chapter["display"]["icon16x16"], "yoto:#public");
+        assert_eq!(chapter["tracks"][0]["display"]["icon16x16"], "yoto:#mine");
+        assert_eq!(
+            chapter["tracks"][1]["display"]["icon16x16"],
+            "yoto:#uploaded"
+        );
+    }
+
+    #[test]
+    fn read_zip_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Stories (5WsQ9).zip");
+        let mut target = Target::create(&path, true).unwrap();
+        target
+            .write("Stories (5WsQ9)/card.json", b"{}", true)
+            .unwrap();
+        target
+            .write("Stories (5WsQ9)/01 - Fox/icon.gif", b"GIF89a", false)
+            .unwrap();
+        target.finish().unwrap();
+
+        let mut source = Source::open(&path).unwrap();
+        assert_eq!(source.read(CARD_FILE).unwrap(), b"{}");
+        assert_eq!(
+            source.find_icon("01 - Fox/icon").as_deref(),
+            Some("01 - Fox/icon.gif")
+        );
+        assert_eq!(source.find_icon("01 - Fox/01 - Part 1.icon"), None);
+    }
+}
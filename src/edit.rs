@@ -0,0 +1,261 @@
+//! Edit operations on the content of existing cards.
+//!
+//! Chapters and tracks are given by their index, from 0. Operations keep the
+//! keys and overlay labels, which playback targets and players may refer to;
+//! [`renumber`] numbers them in order again if wanted, before submitting the
+//! card with [`Client::update_card`](crate::api::Client::update_card).
+
+use crate::error::{Error, Result};
+use crate::model::{Card, Chapter, Track};
+
+fn no_chapter(chapter: usize) -> Error {
+    Error::InvalidCard(format!("no chapter {}", chapter + 1))
+}
+
+fn no_track(chapter: usize, track: usize) -> Error {
+    Error::InvalidCard(format!("no track {} in chapter {}", track + 1, chapter + 1))
+}
+
+fn chapter_mut(card: &mut Card, chapter: usize) -> Result<&mut Chapter> {
+    card.content
+        .chapters
+        .get_mut(chapter)
+        .ok_or_else(|| no_chapter(chapter))
+}
+
+/// Recomputes the duration and size of a chapter from its tracks.
+fn update_totals(chapter: &mut Chapter) {
+    chapter.duration = Some(chapter.tracks.iter().map(|t| t.duration).sum());
+    chapter.file_size = Some(chapter.tracks.iter().map(|t| t.file_size).sum());
+}
+
+pub fn rename_chapter(card: &mut Card, chapter: usize, title: &str) -> Result<()> {
+    chapter_mut(card, chapter)?.title = title.to_string();
+    Ok(())
+}
+
+pub fn rename_track(card: &mut Card, chapter: usize, track: usize, title: &str) -> Result<()> {
+    chapter_mut(card, chapter)?
+        .tracks
+        .get_mut(track)
+        .ok_or_else(|| no_track(chapter, track))?
+        .title = title.to_string();
+    Ok(())
+}
+
+/// Moves a chapter so that it ends up at index `to`.
+pub fn move_chapter(card: &mut Card, from: usize, to: usize) -> Result<()> {
+    let chapters = &mut card.content.chapters;
+    if from >= chapters.len() {
+        return Err(no_chapter(from));
+    }
+    if to >= chapters.len() {
+        return Err(no_chapter(to));
+    }
+    let chapter = chapters.remove(from);
+    chapters.insert(to, chapter);
+    Ok(())
+}
+
+/// Removes a track, and its chapter if it was the only track of it.
+pub fn remove_track(card: &mut Card, chapter: usize, track: usize) -> Result<Track> {
+    let current = chapter_mut(card, chapter)?;
+    if track >= current.tracks.len() {
+        return Err(no_track(chapter, track));
+    }
+    let removed = current.tracks.remove(track);
+    update_totals(current);
+    if current.tracks.is_empty() {
+        card.content.chapters.remove(chapter);
+    }
+    Ok(removed)
+}
+
+/// Moves a track to another chapter, at index `position` of it, or at its end
+/// if not given. The source chapter is removed if it becomes empty.
+pub fn move_track(
+    card: &mut Card,
+    from: (usize, usize),
+    to_chapter: usize,
+    position: Option<usize>,
+) -> Result<()> {
+    let destination = card
+        .content
+        .chapters
+        .get(to_chapter)
+        .ok_or_else(|| no_chapter(to_chapter))?;
+    /* Moving within a chapter frees a slot */
+    let count = destination
+        .tracks
+        .len()
+        .saturating_sub(usize::from(from.0 == to_chapter));
+    let position = position.unwrap_or(count);
+    if position > count {
+        return Err(no_track(to_chapter, position));
+    }
+    let source = chapter_mut(card, from.0)?;
+    if from.1 >= source.tracks.len() {
+        return Err(no_track(from.0, from.1));
+    }
+
+    let track = source.tracks.remove(from.1);
+    update_totals(source);
+    let destination = &mut card.content.chapters[to_chapter];
+    destination.tracks.insert(position, track);
+    update_totals(destination);
+    if card.content.chapters[from.0].tracks.is_empty() {
+        card.content.chapters.remove(from.0);
+    }
+    Ok(())
+}
+
+/// Appends the tracks of the chapter following `chapter` to it, and removes
+/// that chapter.
+pub fn merge_chapters(card: &mut Card, chapter: usize) -> Result<()> {
+    let chapters = &mut card.content.chapters;
+    if chapter + 1 >= chapters.len() {
+        return Err(no_chapter(chapter + 1));
+    }
+    let next = chapters.remove(chapter + 1);
+    let current = &mut chapters[chapter];
+    current.tracks.extend(next.tracks);
+    update_totals(current);
+    Ok(())
+}
+
+/// Returns a chapter key not used by the card yet.
+fn unused_chapter_key(card: &Card) -> String {
+    (card.content.chapters.len() + 1..)
+        .map(|n| format!("{:02}", n))
+        .find(|key| card.content.chapters.iter().all(|c| c.key != *key))
+        .unwrap_or_default()
+}
+
+/// Moves the tracks of a chapter from index `track` on to a new chapter
+/// following it, titled after its first track and with a key of its own.
+pub fn split_chapter(card: &mut Card, chapter: usize, track: usize) -> Result<()> {
+    let current = chapter_mut(card, chapter)?;
+    if track == 0 || track >= current.tracks.len() {
+        return Err(no_track(chapter, track));
+    }
+    let tracks = current.tracks.split_off(track);
+    update_totals(current);
+    let display = current.display.clone();
+
+    let mut new = Chapter::new(&unused_chapter_key(card), &tracks[0].title);
+    new.display = display;
+    let new = tracks.into_iter().fold(new, Chapter::track);
+    card.content.chapters.insert(chapter + 1, new);
+    Ok(())
+}
+
+/// Numbers the keys and overlay labels of chapters and tracks in order, from
+/// 1. Tracks are numbered across the whole card.
+pub fn renumber(card: &mut Card) {
+    let mut number = 0;
+    for (i, chapter) in card.content.chapters.iter_mut().enumerate() {
+        chapter.key = format!("{:02}", i + 1);
+        chapter.overlay_label = Some((i + 1).to_string());
+        for track in chapter.tracks.iter_mut() {
+            number += 1;
+            track.key = format!("{:02}", number);
+            track.overlay_label = number.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{media_url, MediaFormat};
+
+    fn card() -> Card {
+        let track = |n: u64| {
+            let key = format!("{:02}", n);
+            Track::new(
+                &key,
+                &format!("Track {}", n),
+                &media_url(&key),
+                MediaFormat::Aac,
+            )
+            .duration(10 * n)
+        };
+        Card::new("Stories")
+            .chapter(Chapter::new("01", "One").track(track(1)).track(track(2)))
+            .chapter(Chapter::new("02", "Two").track(track(3)))
+            .chapter(Chapter::new("03", "Three").track(track(4)).track(track(5)))
+    }
+
+    fn layout(card: &Card) -> Vec<(String, Vec<String>)> {
+        card.content
+            .chapters
+            .iter()
+            .map(|chapter| {
+                let tracks = chapter.tracks.iter().map(|t| t.title.clone()).collect();
+                (chapter.title.clone(), tracks)
+            })
+            .collect()
+    }
+
+    fn expected(chapters: &[(&str, &[u32])]) -> Vec<(String, Vec<String>)> {
+        chapters
+            .iter()
+            .map(|(title, tracks)| {
+                let tracks = tracks.iter().map(|n| format!("Track {}", n)).collect();
+                (title.to_string(), tracks)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn move_tracks_and_chapters() {
+        let mut card = card();
+        move_track(&mut card, (1, 0), 2, Some(1)).unwrap();
+        assert_eq!(
+            layout(&card),
+            expected(&[("One", &[1, 2]), ("Three", &[4, 3, 5])])
+        );
+        assert_eq!(card.content.chapters[1].duration, Some(120));
+
+        move_track(&mut card, (0, 0), 0, None).unwrap();
+        move_chapter(&mut card, 1, 0).unwrap();
+        assert_eq!(
+            layout(&card),
+            expected(&[("Three", &[4, 3, 5]), ("One", &[2, 1])])
+        );
+        assert!(move_track(&mut card, (0, 0), 1, Some(3)).is_err());
+    }
+
+    #[test]
+    fn merge_split_and_remove() {
+        let mut card = card();
+        merge_chapters(&mut card, 0).unwrap();
+        assert_eq!(
+            layout(&card),
+            expected(&[("One", &[1, 2, 3]), ("Three", &[4, 5])])
+        );
+        split_chapter(&mut card, 0, 1).unwrap();
+        assert_eq!(
+            layout(&card),
+            expected(&[("One", &[1]), ("Track 2", &[2, 3]), ("Three", &[4, 5])])
+        );
+        let keys: Vec<_> = card
+            .content
+            .chapters
+            .iter()
+            .map(|c| c.key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["01", "04", "03"]);
+        remove_track(&mut card, 0, 0).unwrap();
+        rename_track(&mut card, 1, 1, "Five").unwrap();
+        renumber(&mut card);
+
+        let keys: Vec<_> = card.content.chapters[1]
+            .tracks
+            .iter()
+            .map(|t| (t.key.as_str(), t.overlay_label.as_str(), t.title.as_str()))
+            .collect();
+        assert_eq!(keys, vec![("03", "3", "Track 4"), ("04", "4", "Five")]);
+        assert_eq!(card.content.chapters[1].key, "02");
+    }
+}
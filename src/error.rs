@@ -0,0 +1,49 @@
+//! Errors returned by the library.
+
+use reqwest::StatusCode;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("HTTP error {status}: {body}")]
+    Http { status: StatusCode, body: String },
+    #[error("failed to decode response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not authenticated")]
+    NotAuthenticated,
+    #[error("authentication token expired")]
+    AuthExpired,
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("login code expired before being confirmed")]
+    LoginExpired,
+    #[error("login was denied by the user")]
+    AccessDenied,
+    #[error("login was cancelled")]
+    LoginCancelled,
+    #[error("token store error: {0}")]
+    Store(String),
+    #[error("MQTT error: {0}")]
+    Mqtt(String),
+    #[error("resource not found")]
+    NotFound,
+    #[error("rate limited by server")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("timed out waiting for audio transcoding")]
+    TranscodeTimeout,
+    #[error("unsupported media format: {0}")]
+    UnsupportedFormat(String),
+    #[error("invalid card: {0}")]
+    InvalidCard(String),
+    #[error("invalid manifest: {0}")]
+    Manifest(String),
+    #[error("invalid alarm: {0}")]
+    InvalidAlarm(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
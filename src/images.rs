@@ -0,0 +1,121 @@
+//! Display icons made from pictures or text.
+//!
+//! Players show 16x16 pixel icons: the functions here return PNG data ready
+//! for [`Client::upload_display_icon`](crate::api::Client::upload_display_icon).
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use rusttype::{point, Font, Scale};
+use std::io::Cursor;
+
+use crate::error::{Error, Result};
+
+/// Width and height of display icons, in pixels.
+pub const ICON_SIZE: u32 = 16;
+
+/// Scales a picture down to a display icon, cropping it to a square.
+pub fn icon_from_image(data: &[u8]) -> Result<Vec<u8>> {
+    let image =
+        image::load_from_memory(data).map_err(|e| Error::UnsupportedFormat(e.to_string()))?;
+    encode_png(image.resize_to_fill(ICON_SIZE, ICON_SIZE, FilterType::Triangle))
+}
+
+/// Draws `text` centered on a transparent display icon, shrinking it to fit.
+///
+/// `font` is the content of a TrueType or OpenType font file.
+pub fn text_icon(font: &[u8], text: &str, color: [u8; 3]) -> Result<Vec<u8>> {
+    let font = Font::try_from_bytes(font)
+        .ok_or_else(|| Error::UnsupportedFormat("invalid font".to_string()))?;
+    let size = ICON_SIZE as f32;
+
+    /* Measure the text at full height first, then shrink it to fit */
+    let width = font
+        .layout(text, Scale::uniform(size), point(0.0, 0.0))
+        .filter_map(|glyph| glyph.pixel_bounding_box())
+        .map(|bounds| bounds.max.x)
+        .max()
+        .unwrap_or(0) as f32;
+    let scale = Scale::uniform(if width > size {
+        size * size / width
+    } else {
+        size
+    });
+
+    let glyphs: Vec<_> = font
+        .layout(text, scale, point(0.0, font.v_metrics(scale).ascent))
+        .collect();
+    let bounds: Vec<_> = glyphs
+        .iter()
+        .filter_map(|g| g.pixel_bounding_box())
+        .collect();
+    let mut icon = RgbaImage::new(ICON_SIZE, ICON_SIZE);
+    let (Some(min_x), Some(max_x), Some(min_y), Some(max_y)) = (
+        bounds.iter().map(|b| b.min.x).min(),
+        bounds.iter().map(|b| b.max.x).max(),
+        bounds.iter().map(|b| b.min.y).min(),
+        bounds.iter().map(|b| b.max.y).max(),
+    ) else {
+        return encode_png(DynamicImage::ImageRgba8(icon));
+    };
+    let offset_x = (ICON_SIZE as i32 - (max_x - min_x)) / 2 - min_x;
+    let offset_y = (ICON_SIZE as i32 - (max_y - min_y)) / 2 - min_y;
+
+    for glyph in &glyphs {
+        let Some(bounds) = glyph.pixel_bounding_box() else {
+            continue;
+        };
+        glyph.draw(|x, y, coverage| {
+            let x = x as i32 + bounds.min.x + offset_x;
+            let y = y as i32 + bounds.min.y + offset_y;
+            if (0..ICON_SIZE as i32).contains(&x) && (0..ICON_SIZE as i32).contains(&y) {
+                let alpha = (coverage * 255.0).round() as u8;
+                let pixel = icon.get_pixel_mut(x as u32, y as u32);
+                if alpha > pixel[3] {
+                    *pixel = Rgba([color[0], color[1], color[2], alpha]);
+                }
+            }
+        });
+    }
+    encode_png(DynamicImage::ImageRgba8(icon))
+}
+
+fn encode_png(image: DynamicImage) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+        .map_err(|e| Error::UnsupportedFormat(e.to_string()))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage};
+
+    #[test]
+    fn icon_from_picture() {
+        let picture = RgbImage::from_fn(48, 32, |x, _| {
+            if x < 24 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        let data = encode_png(DynamicImage::ImageRgb8(picture)).unwrap();
+
+        let icon = image::load_from_memory(&icon_from_image(&data).unwrap()).unwrap();
+        assert_eq!(icon.dimensions(), (ICON_SIZE, ICON_SIZE));
+        /* Cropped to the center, keeping both halves */
+        assert_eq!(icon.get_pixel(0, 8)[0], 255);
+        assert_eq!(icon.get_pixel(15, 8)[2], 255);
+
+        assert!(matches!(
+            icon_from_image(b"not an image"),
+            Err(Error::UnsupportedFormat(_))
+        ));
+        assert!(matches!(
+            text_icon(b"not a font", "A", [255, 255, 255]),
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,60 @@
+//! Client library for the Yoto player API.
+//!
+//! The [`api::Client`] talks to the REST API to manage cards, media and
+//! devices, authenticating with the OAuth device code flow:
+//!
+//! ```no_run
+//! use yoto::api::Client;
+//! use yoto::store::FileStore;
+//! use std::sync::Arc;
+//!
+//! # fn main() -> yoto::Result<()> {
+//! let client = Client::builder("my-client-id")
+//!     .token_store(Arc::new(FileStore::new("token.json")))
+//!     .build()?;
+//! if client.token().is_none() {
+//!     let mut login = client.start_login()?;
+//!     println!("Go to {}", login.verification_uri_complete);
+//!     client.wait_login(&mut login, |_, _| true)?;
+//! }
+//! for card in client.get_cards()? {
+//!     println!("{}: {}", card.card_id, card.title);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Optional features:
+//! - `async`: [`async_api::AsyncClient`], built on tokio.
+//! - `images`: display icons made from pictures or text, see [`images`].
+//! - `manifest`: cards kept as YAML or TOML manifests, see [`manifest`].
+//! - `keyring`: [`store::KeyringStore`], storing the token in the OS keyring.
+//! - `mqtt`: messages exchanged with players over MQTT, and a simulated
+//!   player.
+//! - `tags`: titles of cards made from audio files read from their tags.
+//! - `cli`: the `yoto-cli` command line tool.
+
+pub mod alarm;
+pub mod api;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod backup;
+pub mod edit;
+pub mod error;
+#[cfg(feature = "images")]
+pub mod images;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+pub mod mirror;
+pub mod model;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod playlist;
+#[cfg(feature = "mqtt")]
+pub mod simulator;
+pub mod store;
+#[cfg(test)]
+mod testing;
+pub mod watch;
+
+pub use error::{Error, Result};
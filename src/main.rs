@@ -1,16 +1,748 @@
-mod api;
-mod model;
-
-use clap::{App, Arg};
-use keyring::Entry;
+use chrono::{Local, NaiveTime, Weekday};
+use clap::{App, Arg, ArgMatches};
+use std::fmt::Display;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use yoto::alarm::{self, Alarm};
+use yoto::api;
+use yoto::backup;
+use yoto::edit;
+use yoto::manifest;
+use yoto::mirror::{self, Deleted};
+use yoto::model::{Brightness, Card, Device, DeviceConfig, DeviceStatus};
+use yoto::mqtt::{CardTarget, Command, DeviceMessage, Event, MqttClient, MqttConfig};
+use yoto::playlist::{self, PlaylistOptions};
+use yoto::simulator::Simulator;
+use yoto::store::{self, TokenStore};
+use yoto::watch::{Change, StatusWatcher};
 
 static CLIENT_ID: &str = "Y5NOImSXBO6vCmiVN7hmFgSe4WKo71hO";
 
-fn store_token(entry: &Entry, client: &api::Client) {
-    entry
-        .set_password(serde_json::to_string(&client.token).unwrap().as_ref())
-        .expect("Failed to save new token");
+fn token_store(m: &ArgMatches) -> Result<Arc<dyn TokenStore>, String> {
+    match m.value_of("token-store").unwrap_or("keyring") {
+        "keyring" => match store::KeyringStore::new() {
+            Ok(store) => Ok(Arc::new(store)),
+            Err(err) => Err(err.to_string()),
+        },
+        "file" => match m
+            .value_of("token-file")
+            .map(Into::into)
+            .or_else(store::FileStore::default_path)
+        {
+            Some(path) => Ok(Arc::new(store::FileStore::new(path))),
+            None => Err("No path given for the token file".to_string()),
+        },
+        "env" => Ok(Arc::new(store::EnvStore::new())),
+        "memory" => Ok(Arc::new(store::MemoryStore::default())),
+        other => Err(format!("Unknown token store \"{}\"", other)),
+    }
+}
+
+/// Finds a device by identifier or, failing that, by name.
+fn find_device(client: &api::Client, name_or_id: &str) -> Result<Device, String> {
+    let devices = client
+        .get_devices()
+        .map_err(|err| format!("Error while retrieving devices: {}", err))?;
+    match_device(devices, name_or_id)
+}
+
+/// Picks the device with the given identifier or, failing that, the only one
+/// with the given name, ignoring case.
+fn match_device(devices: Vec<Device>, name_or_id: &str) -> Result<Device, String> {
+    let mut matches: Vec<Device> = Vec::new();
+    for device in devices {
+        if device.id == name_or_id {
+            return Ok(device);
+        }
+        if device.name.eq_ignore_ascii_case(name_or_id) {
+            matches.push(device);
+        }
+    }
+    match matches.len() {
+        0 => Err(format!("No device named \"{}\"", name_or_id)),
+        1 => Ok(matches.remove(0)),
+        _ => Err(format!(
+            "Several devices are named \"{}\", use the device ID instead",
+            name_or_id
+        )),
+    }
+}
+
+/// Formats a number of bytes with a decimal unit, e.g. "1.6 GB".
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn print_status(device: &Device, status: &DeviceStatus, cards: &[Card]) {
+    let online = if status.online() { "online" } else { "offline" };
+    println!("{} ({}): {}", device.name, device.id, online);
+
+    let charging = if status.charging() {
+        "charging"
+    } else {
+        "not charging"
+    };
+    println!(
+        "  Battery:     {}% ({}, {})",
+        status.battery_level(),
+        charging,
+        status.power_source()
+    );
+    println!(
+        "  Volume:      {}% (system {}%)",
+        status.user_volume(),
+        status.system_volume()
+    );
+
+    let card = status.active_card();
+    if card.is_empty() || card == "none" {
+        println!("  Card:        none");
+    } else {
+        match cards.iter().find(|c| c.card_id == card) {
+            Some(c) => println!("  Card:        {} ({})", c.title, card),
+            None => println!("  Card:        {}", card),
+        }
+    }
+
+    println!(
+        "  Wi-Fi:       {} ({} dBm)",
+        status.network_ssid(),
+        status.wifi_strength()
+    );
+    let total = status.total_disk_space();
+    println!(
+        "  Storage:     {} used of {}",
+        format_size(total.saturating_sub(status.free_disk_space())),
+        format_size(total)
+    );
+    println!("  Temperature: {}°C", status.temperature());
+    println!("  Updated:     {}", status.updated_at());
+}
+
+/// JSON output of the status of a device or, with `all`, of a list of
+/// devices with their identifier and name.
+fn statuses_json(
+    statuses: &[(Device, DeviceStatus)],
+    all: bool,
+) -> serde_json::Result<serde_json::Value> {
+    if !all {
+        return serde_json::to_value(&statuses[0].1);
+    }
+    statuses
+        .iter()
+        .map(|(device, status)| {
+            Ok(serde_json::json!({
+                "deviceId": device.id,
+                "name": device.name,
+                "status": serde_json::to_value(status)?,
+            }))
+        })
+        .collect()
+}
+
+/// Shows the status of one device, or all of them, as text or JSON.
+fn device_status(client: &api::Client, arg: &ArgMatches) -> Result<(), String> {
+    let devices = match arg.value_of("device") {
+        Some(device) => vec![find_device(client, device)?],
+        None => {
+            let devices = client
+                .get_devices()
+                .map_err(|err| format!("Error while retrieving devices: {}", err))?;
+            if devices.len() > 1 && !arg.is_present("all") {
+                return Err("Several devices are linked, give a name or use --all".to_string());
+            }
+            devices
+        }
+    };
+    if devices.is_empty() {
+        return Err("No devices linked with this account".to_string());
+    }
+
+    let mut statuses = Vec::new();
+    for device in devices {
+        let status = client.get_device_status(&device.id).map_err(|err| {
+            format!(
+                "Error while retrieving the status of \"{}\": {}",
+                device.name, err
+            )
+        })?;
+        statuses.push((device, status));
+    }
+
+    if arg.is_present("json") {
+        let json = statuses_json(&statuses, arg.is_present("all"))
+            .and_then(|json| serde_json::to_string_pretty(&json));
+        println!("{}", json.map_err(|err| err.to_string())?);
+        return Ok(());
+    }
+
+    let playing = statuses
+        .iter()
+        .any(|(_, status)| !matches!(status.active_card(), "" | "none"));
+    let cards = if playing {
+        client
+            .get_cards()
+            .map_err(|err| format!("Error while retrieving cards: {}", err))?
+    } else {
+        Vec::new()
+    };
+    for (i, (device, status)) in statuses.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_status(device, status, &cards);
+    }
+    Ok(())
+}
+
+fn or_unset<T: Display>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn brightness_name(brightness: &Option<Brightness>) -> String {
+    match brightness {
+        Some(Brightness::Auto) => "auto".to_string(),
+        Some(Brightness::Level(level)) => format!("{}%", level),
+        Some(Brightness::Unknown(value)) => value.clone(),
+        None => "-".to_string(),
+    }
+}
+
+fn print_config(device: &Device, config: &DeviceConfig) {
+    let time = |time: &Option<NaiveTime>| or_unset(&time.map(|t| t.format("%H:%M")));
+    println!("Settings of {} ({}):", device.name, device.id);
+    println!("  Day starts at:    {}", time(&config.day_time));
+    println!("  Night starts at:  {}", time(&config.night_time));
+    println!(
+        "  Max volume:       {} (night {})",
+        or_unset(&config.max_volume_limit),
+        or_unset(&config.night_max_volume_limit)
+    );
+    println!(
+        "  Brightness:       {} (night {})",
+        brightness_name(&config.day_display_brightness),
+        brightness_name(&config.night_display_brightness)
+    );
+    println!(
+        "  Clock format:     {} hours",
+        or_unset(&config.hour_format)
+    );
+    println!(
+        "  Nightlight:       {} (night {})",
+        or_unset(&config.ambient_colour),
+        or_unset(&config.night_ambient_colour)
+    );
+    println!(
+        "  Shutdown timeout: {} s",
+        or_unset(&config.shutdown_timeout)
+    );
+    let bluetooth = config
+        .bluetooth_enabled
+        .map(|on| if on { "on" } else { "off" });
+    println!("  Bluetooth:        {}", or_unset(&bluetooth));
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|_| format!("Invalid time \"{}\", expected HH:MM", value))
+}
+
+fn parse_volume(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(volume) if volume <= 16 => Ok(volume),
+        _ => Err(format!("Invalid volume \"{}\", expected 0 to 16", value)),
+    }
+}
+
+fn parse_brightness(value: &str) -> Result<Brightness, String> {
+    if value == "auto" {
+        return Ok(Brightness::Auto);
+    }
+    match value.parse::<u32>() {
+        Ok(level) if level <= 100 => Ok(Brightness::Level(level)),
+        _ => Err(format!(
+            "Invalid brightness \"{}\", expected auto or 0 to 100",
+            value
+        )),
+    }
+}
+
+fn parse_colour(value: &str) -> Result<String, String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(format!("#{}", hex.to_ascii_lowercase()))
+    } else {
+        Err(format!("Invalid colour \"{}\", expected #rrggbb", value))
+    }
+}
+
+/// Shows or changes the settings of a device.
+fn device_config(client: &api::Client, m: &ArgMatches) -> Result<(), String> {
+    let (action, arg) = m.subcommand().ok_or("Invalid config command")?;
+    let device = find_device(client, arg.value_of("device").unwrap())?;
+    let mut config = client
+        .get_device_config(&device.id)
+        .map_err(|err| format!("Error while retrieving settings: {}", err))?;
+
+    if action == "get" {
+        if arg.is_present("json") {
+            let json = serde_json::to_string_pretty(&config).map_err(|err| err.to_string())?;
+            println!("{}", json);
+        } else {
+            print_config(&device, &config);
+        }
+        return Ok(());
+    }
+
+    let mut changed = false;
+    let mut set = |name: &str| {
+        let value = arg.value_of(name);
+        changed |= value.is_some();
+        value
+    };
+    if let Some(value) = set("day-time") {
+        config.day_time = Some(parse_time(value)?);
+    }
+    if let Some(value) = set("night-time") {
+        config.night_time = Some(parse_time(value)?);
+    }
+    if let Some(value) = set("max-volume") {
+        config.max_volume_limit = Some(parse_volume(value)?);
+    }
+    if let Some(value) = set("night-max-volume") {
+        config.night_max_volume_limit = Some(parse_volume(value)?);
+    }
+    if let Some(value) = set("brightness") {
+        config.day_display_brightness = Some(parse_brightness(value)?);
+    }
+    if let Some(value) = set("night-brightness") {
+        config.night_display_brightness = Some(parse_brightness(value)?);
+    }
+    if let Some(value) = set("clock-format") {
+        config.hour_format = value.parse().ok();
+    }
+    if let Some(value) = set("nightlight") {
+        config.ambient_colour = Some(parse_colour(value)?);
+    }
+    if let Some(value) = set("night-nightlight") {
+        config.night_ambient_colour = Some(parse_colour(value)?);
+    }
+    if let Some(value) = set("shutdown-timeout") {
+        let timeout = value
+            .parse()
+            .map_err(|_| format!("Invalid timeout \"{}\"", value))?;
+        config.shutdown_timeout = Some(timeout);
+    }
+    if let Some(value) = set("bluetooth") {
+        config.bluetooth_enabled = Some(value == "on");
+    }
+    if !changed {
+        return Err("No setting to change".to_string());
+    }
+
+    client
+        .update_device_config(&device.id, &config)
+        .map_err(|err| format!("Error while updating settings: {}", err))?;
+    println!("Updated settings of {}", device.name);
+    Ok(())
+}
+
+const WEEK: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Parses the days of an alarm: "once", "daily", "weekdays", "weekends" or a
+/// list of days such as "mon,wed,fri".
+fn parse_days(value: &str) -> Result<[bool; 7], String> {
+    match value {
+        "once" => return Ok([false; 7]),
+        "daily" => return Ok([true; 7]),
+        "weekdays" => return Ok([true, true, true, true, true, false, false]),
+        "weekends" => return Ok([false, false, false, false, false, true, true]),
+        _ => (),
+    }
+    let mut days = [false; 7];
+    for day in value.split(',') {
+        let day = day
+            .trim()
+            .parse::<Weekday>()
+            .map_err(|_| format!("Invalid day \"{}\"", day))?;
+        days[day.num_days_from_monday() as usize] = true;
+    }
+    Ok(days)
+}
+
+fn format_days(days: &[bool; 7]) -> String {
+    match days {
+        [false, false, false, false, false, false, false] => "once".to_string(),
+        [true, true, true, true, true, true, true] => "daily".to_string(),
+        _ => WEEK
+            .iter()
+            .zip(days.iter())
+            .filter(|(_, on)| **on)
+            .map(|(day, _)| day.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+fn print_alarms(alarms: &[Alarm]) {
+    if alarms.is_empty() {
+        println!("No alarms set.");
+        return;
+    }
+    println!("Alarms:");
+    for (i, alarm) in alarms.iter().enumerate() {
+        println!(
+            "  {}: {} {} ({}){}",
+            i + 1,
+            alarm.time.format("%H:%M"),
+            format_days(&alarm.days),
+            alarm.sound,
+            if alarm.enabled { "" } else { ", disabled" }
+        );
+    }
+}
+
+/// Parses the properties of an alarm given on the command line, into a
+/// function setting them.
+fn alarm_changes(arg: &ArgMatches) -> Result<impl FnOnce(&mut Alarm), String> {
+    let time = arg.value_of("time").map(parse_time).transpose()?;
+    let days = arg.value_of("days").map(parse_days).transpose()?;
+    let sound = arg.value_of("sound").map(str::to_string);
+    let enabled = match (arg.is_present("enable"), arg.is_present("disable")) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    Ok(move |alarm: &mut Alarm| {
+        alarm.time = time.unwrap_or(alarm.time);
+        alarm.days = days.unwrap_or(alarm.days);
+        if let Some(sound) = sound {
+            alarm.sound = sound;
+        }
+        alarm.enabled = enabled.unwrap_or(alarm.enabled);
+    })
+}
+
+/// Lists or changes the alarms of a device.
+fn device_alarms(client: &api::Client, m: &ArgMatches) -> Result<(), String> {
+    let (action, arg) = m.subcommand().ok_or("Invalid alarm command")?;
+    let device = find_device(client, arg.value_of("device").unwrap())?;
+    let alarms = match action {
+        "list" => alarm::list_alarms(client, &device.id),
+        "add" => {
+            let time = parse_time(arg.value_of("time").unwrap())?;
+            let mut alarm = Alarm::new(time, "");
+            alarm_changes(arg)?(&mut alarm);
+            alarm.validate().map_err(|err| err.to_string())?;
+            alarm::add_alarm(client, &device.id, alarm)
+        }
+        "update" => {
+            let index = parse_index(arg, "alarm")?;
+            alarm::edit_alarm(client, &device.id, index, alarm_changes(arg)?)
+        }
+        _ => alarm::remove_alarm(client, &device.id, parse_index(arg, "alarm")?),
+    };
+    print_alarms(&alarms.map_err(|err| err.to_string())?);
+    Ok(())
+}
+
+/// Parses an interval given in seconds, or with a unit, e.g. "30s" or "5m".
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => 0,
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 && scale > 0 => Ok(Duration::from_secs(number * scale)),
+        _ => Err(format!("Invalid interval \"{}\"", value)),
+    }
+}
+
+/// Prints the changes of the status of a device until interrupted.
+fn watch_device(client: &api::Client, arg: &ArgMatches) -> Result<(), String> {
+    let device = find_device(client, arg.value_of("device").unwrap())?;
+    let interval = parse_interval(arg.value_of("interval").unwrap())?;
+    /* Card titles are only used to describe the changes */
+    let cards = client.get_cards().unwrap_or_default();
+
+    let mut watcher = StatusWatcher::new(client, &device.id, interval);
+    println!("Watching {} ({})", device.name, device.id);
+    watcher.run(|event| {
+        let time = Local::now().format("%H:%M:%S");
+        let change = match event {
+            Ok(change) => change,
+            Err(err) => {
+                println!("[{}] Error while retrieving the status: {}", time, err);
+                return true;
+            }
+        };
+        let card = match &change {
+            Change::Card(Some(id)) => cards.iter().find(|card| &card.card_id == id),
+            _ => None,
+        };
+        match card {
+            Some(card) => println!(
+                "[{}] card \"{}\" ({}) inserted",
+                time, card.title, card.card_id
+            ),
+            None => println!("[{}] {}", time, change),
+        }
+        true
+    });
+    Ok(())
+}
+
+/// Parses a chapter or track number, from 1, into an index.
+fn parse_index(m: &ArgMatches, name: &str) -> Result<usize, String> {
+    let value = m.value_of(name).unwrap_or_default();
+    match value.parse::<usize>() {
+        Ok(number) if number > 0 => Ok(number - 1),
+        _ => Err(format!("Invalid {} \"{}\"", name, value)),
+    }
+}
+
+/// Applies an edit operation to a card and submits it.
+fn edit_card(client: &api::Client, id: &str, m: &ArgMatches) -> Result<Card, String> {
+    let mut card = client.get_card(id, false).map_err(|err| err.to_string())?;
+    let result = match m.subcommand() {
+        Some(("rename-chapter", arg)) => edit::rename_chapter(
+            &mut card,
+            parse_index(arg, "chapter")?,
+            arg.value_of("title").unwrap(),
+        ),
+        Some(("rename-track", arg)) => edit::rename_track(
+            &mut card,
+            parse_index(arg, "chapter")?,
+            parse_index(arg, "track")?,
+            arg.value_of("title").unwrap(),
+        ),
+        Some(("move-chapter", arg)) => edit::move_chapter(
+            &mut card,
+            parse_index(arg, "chapter")?,
+            parse_index(arg, "to")?,
+        ),
+        Some(("move-track", arg)) => {
+            let position = if arg.is_present("position") {
+                Some(parse_index(arg, "position")?)
+            } else {
+                None
+            };
+            edit::move_track(
+                &mut card,
+                (parse_index(arg, "chapter")?, parse_index(arg, "track")?),
+                parse_index(arg, "to")?,
+                position,
+            )
+        }
+        Some(("remove-track", arg)) => edit::remove_track(
+            &mut card,
+            parse_index(arg, "chapter")?,
+            parse_index(arg, "track")?,
+        )
+        .map(|_| ()),
+        Some(("merge", arg)) => edit::merge_chapters(&mut card, parse_index(arg, "chapter")?),
+        Some(("split", arg)) => edit::split_chapter(
+            &mut card,
+            parse_index(arg, "chapter")?,
+            parse_index(arg, "track")?,
+        ),
+        Some(("renumber", _)) => {
+            edit::renumber(&mut card);
+            Ok(())
+        }
+        _ => return Err("Invalid edit command".to_string()),
+    };
+    result.map_err(|err| err.to_string())?;
+    client.update_card(&card).map_err(|err| err.to_string())
+}
+
+/// Parses a position given as seconds, `m:ss` or `h:mm:ss`.
+fn parse_position(value: &str) -> Result<u32, String> {
+    value
+        .split(':')
+        .try_fold(0u32, |total, part| {
+            let part = part.parse::<u32>().ok()?;
+            total.checked_mul(60)?.checked_add(part)
+        })
+        .ok_or_else(|| format!("Invalid position \"{}\"", value))
+}
+
+fn mqtt_config(m: &ArgMatches) -> Result<MqttConfig, String> {
+    match m.value_of("mqtt-broker") {
+        Some(broker) => {
+            let (host, port) = match broker.rsplit_once(':') {
+                Some((host, port)) => match port.parse::<u16>() {
+                    Ok(port) => (host, port),
+                    Err(_) => return Err(format!("Invalid MQTT broker \"{}\"", broker)),
+                },
+                None => (broker, 1883),
+            };
+            Ok(MqttConfig::local(host, port))
+        }
+        None => Ok(MqttConfig::default()),
+    }
+}
+
+fn connect_mqtt(client: &api::Client, m: &ArgMatches) -> Result<MqttClient, String> {
+    let config = mqtt_config(m)?;
+    /* Make sure the token is fresh before handing it to the broker */
+    client.refresh_token().map_err(|err| err.to_string())?;
+    let token = client.token().ok_or("Not authenticated")?;
+    MqttClient::connect(&config, &token).map_err(|err| err.to_string())
+}
+
+/// Sends a command to a device over MQTT and waits for the broker to
+/// acknowledge it.
+fn send_command(
+    client: &api::Client,
+    m: &ArgMatches,
+    device: &str,
+    command: Command,
+) -> Result<(), String> {
+    let device = find_device(client, device)?;
+    let mut mqtt = connect_mqtt(client, m)?;
+    mqtt.send(&device.id, &command)
+        .and_then(|_| mqtt.flush(Duration::from_secs(10)))
+        .and_then(|_| mqtt.disconnect())
+        .map_err(|err| err.to_string())
+}
+
+/// Target restarting the track described by `event` at `position`.
+fn seek_target(event: &Event, position: u32) -> Result<CardTarget, String> {
+    let card_id = event.card_id.as_deref().ok_or("No card is playing")?;
+    if let Some(length) = event.track_length.filter(|&length| position >= length) {
+        return Err(format!("The track is only {} seconds long", length));
+    }
+    let mut target = CardTarget::new(card_id).seconds_in(position);
+    if let Some(chapter) = &event.chapter_key {
+        target = target.chapter(chapter);
+    }
+    if let Some(track) = &event.track_key {
+        target = target.track(track);
+    }
+    Ok(target)
+}
+
+/// Asks a device for its playback events and waits for the reply.
+fn playback_event(mqtt: &mut MqttClient, device_id: &str) -> Result<Event, String> {
+    mqtt.subscribe(device_id)
+        .and_then(|_| mqtt.send(device_id, &Command::GetEvents))
+        .map_err(|err| err.to_string())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let message = match mqtt.recv(timeout).map_err(|err| err.to_string())? {
+            Some(message) => message,
+            None => return Err("The device did not report what it is playing".to_string()),
+        };
+        if let Ok(Some((id, DeviceMessage::Event(event)))) = DeviceMessage::parse(&message) {
+            if id == device_id {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+/// Moves to a position in the track playing on a device. Players have no
+/// seek command, so the track is started again from that position.
+fn seek(client: &api::Client, m: &ArgMatches, device: &str, position: u32) -> Result<(), String> {
+    let device = find_device(client, device)?;
+    let mut mqtt = connect_mqtt(client, m)?;
+    let target = seek_target(&playback_event(&mut mqtt, &device.id)?, position)?;
+    mqtt.send(&device.id, &Command::Start(target))
+        .and_then(|_| mqtt.flush(Duration::from_secs(10)))
+        .and_then(|_| mqtt.disconnect())
+        .map_err(|err| err.to_string())
+}
+
+/// Loads a card from a JSON file, either bare or as returned by the content
+/// endpoint.
+fn load_card(path: &str) -> Result<Card, String> {
+    let data = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut value: serde_json::Value =
+        serde_json::from_str(&data).map_err(|err| format!("{}: {}", path, err))?;
+    if let Some(card) = value.get_mut("card") {
+        value = card.take();
+    }
+    serde_json::from_value(value).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Runs a simulated player on the configured broker, or on a local one if
+/// none is configured.
+fn simulate(m: &ArgMatches, arg: &ArgMatches) -> Result<(), String> {
+    let mut simulator = Simulator::new(arg.value_of("device").unwrap());
+    for path in arg.values_of("card").into_iter().flatten() {
+        simulator.add_card(load_card(path)?);
+    }
+    let config = match m.value_of("mqtt-broker") {
+        Some(_) => mqtt_config(m)?,
+        None => MqttConfig::local("localhost", 1883),
+    };
+
+    let mut mqtt =
+        MqttClient::connect(&config, &api::Token::default()).map_err(|err| err.to_string())?;
+    println!(
+        "Simulating device {} on {}:{}",
+        simulator.device_id(),
+        config.host,
+        config.port
+    );
+    simulator.run(&mut mqtt).map_err(|err| err.to_string())
+}
+
+fn alarm_args(app: App<'static>) -> App<'static> {
+    app.arg(
+        Arg::with_name("days")
+            .long("days")
+            .takes_value(true)
+            .help("Days to ring: once, daily, weekdays, weekends or e.g. mon,wed"),
+    )
+    .arg(
+        Arg::with_name("sound")
+            .long("sound")
+            .takes_value(true)
+            .help("ID of the alarm sound or of the card to play"),
+    )
+    .arg(
+        Arg::with_name("enable")
+            .long("enable")
+            .conflicts_with("disable")
+            .help("Enable the alarm"),
+    )
+    .arg(
+        Arg::with_name("disable")
+            .long("disable")
+            .help("Disable the alarm"),
+    )
 }
 
 fn main() {
@@ -18,64 +750,501 @@ fn main() {
         .author("Louis-Francis Ratté-Boulianne, louis-francis@ratte-boulianne.com")
         .version("0.1.0")
         .about("Tool to create and manage Yoto cards and devices")
+        .arg(
+            Arg::with_name("api-url")
+                .long("api-url")
+                .env("YOTO_API_URL")
+                .global(true)
+                .takes_value(true)
+                .help("Base URL of the Yoto API"),
+        )
+        .arg(
+            Arg::with_name("auth-url")
+                .long("auth-url")
+                .env("YOTO_AUTH_URL")
+                .global(true)
+                .takes_value(true)
+                .help("URL of the OAuth device code endpoint"),
+        )
+        .arg(
+            Arg::with_name("token-url")
+                .long("token-url")
+                .env("YOTO_TOKEN_URL")
+                .global(true)
+                .takes_value(true)
+                .help("URL of the OAuth token endpoint"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .env("YOTO_TIMEOUT")
+                .global(true)
+                .takes_value(true)
+                .help("HTTP request timeout in seconds"),
+        )
+        .arg(
+            Arg::with_name("token-store")
+                .long("token-store")
+                .env("YOTO_TOKEN_STORE")
+                .global(true)
+                .takes_value(true)
+                .possible_values(["keyring", "file", "env", "memory"])
+                .help("Where to store the authentication token"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .env("YOTO_TOKEN_FILE")
+                .global(true)
+                .takes_value(true)
+                .help("Path of the token file used by the \"file\" token store"),
+        )
+        .arg(
+            Arg::with_name("mqtt-broker")
+                .long("mqtt-broker")
+                .env("YOTO_MQTT_BROKER")
+                .global(true)
+                .takes_value(true)
+                .help("Use a local MQTT broker (host[:port]) instead of the Yoto one"),
+        )
         .subcommand(App::new("login"))
         .subcommand(App::new("logout"))
         .subcommand(App::new("devices"))
+        .subcommand(
+            App::new("device")
+                .subcommand(
+                    App::new("watch")
+                        .about("Print the changes of the status of a device")
+                        .arg(Arg::with_name("device").index(1).required(true))
+                        .arg(
+                            Arg::with_name("interval")
+                                .long("interval")
+                                .takes_value(true)
+                                .default_value("30s")
+                                .help("Time between polls, e.g. 30s or 5m"),
+                        ),
+                )
+                .subcommand(
+                    App::new("alarm")
+                        .about("List or change the alarms of a device, numbered from 1")
+                        .subcommand(
+                            App::new("list").arg(Arg::with_name("device").index(1).required(true)),
+                        )
+                        .subcommand(
+                            alarm_args(
+                                App::new("add")
+                                    .arg(Arg::with_name("device").index(1).required(true))
+                                    .arg(
+                                        Arg::with_name("time")
+                                            .index(2)
+                                            .required(true)
+                                            .help("Time of the alarm (HH:MM)"),
+                                    ),
+                            )
+                            .mut_arg("sound", |arg| arg.required(true)),
+                        )
+                        .subcommand(alarm_args(
+                            App::new("update")
+                                .arg(Arg::with_name("device").index(1).required(true))
+                                .arg(Arg::with_name("alarm").index(2).required(true))
+                                .arg(
+                                    Arg::with_name("time")
+                                        .long("time")
+                                        .takes_value(true)
+                                        .help("Time of the alarm (HH:MM)"),
+                                ),
+                        ))
+                        .subcommand(
+                            App::new("remove")
+                                .arg(Arg::with_name("device").index(1).required(true))
+                                .arg(Arg::with_name("alarm").index(2).required(true)),
+                        ),
+                )
+                .subcommand(
+                    App::new("config")
+                        .about("Show or change the settings of a device")
+                        .subcommand(
+                            App::new("get")
+                                .arg(Arg::with_name("device").index(1).required(true))
+                                .arg(
+                                    Arg::with_name("json")
+                                        .long("json")
+                                        .help("Print the settings as returned by the API"),
+                                ),
+                        )
+                        .subcommand(
+                            App::new("set")
+                                .arg(Arg::with_name("device").index(1).required(true))
+                                .arg(
+                                    Arg::with_name("day-time")
+                                        .long("day-time")
+                                        .takes_value(true)
+                                        .help("Time at which the day starts (HH:MM)"),
+                                )
+                                .arg(
+                                    Arg::with_name("night-time")
+                                        .long("night-time")
+                                        .takes_value(true)
+                                        .help("Time at which the night starts (HH:MM)"),
+                                )
+                                .arg(
+                                    Arg::with_name("max-volume")
+                                        .long("max-volume")
+                                        .takes_value(true)
+                                        .help("Maximum volume during the day, from 0 to 16"),
+                                )
+                                .arg(
+                                    Arg::with_name("night-max-volume")
+                                        .long("night-max-volume")
+                                        .takes_value(true)
+                                        .help("Maximum volume during the night, from 0 to 16"),
+                                )
+                                .arg(
+                                    Arg::with_name("brightness")
+                                        .long("brightness")
+                                        .takes_value(true)
+                                        .help("Day display brightness, auto or 0 to 100"),
+                                )
+                                .arg(
+                                    Arg::with_name("night-brightness")
+                                        .long("night-brightness")
+                                        .takes_value(true)
+                                        .help("Night display brightness, auto or 0 to 100"),
+                                )
+                                .arg(
+                                    Arg::with_name("clock-format")
+                                        .long("clock-format")
+                                        .takes_value(true)
+                                        .possible_values(["12", "24"])
+                                        .help("Clock format of the display, in hours"),
+                                )
+                                .arg(
+                                    Arg::with_name("nightlight")
+                                        .long("nightlight")
+                                        .takes_value(true)
+                                        .help("Nightlight colour during the day (#rrggbb)"),
+                                )
+                                .arg(
+                                    Arg::with_name("night-nightlight")
+                                        .long("night-nightlight")
+                                        .takes_value(true)
+                                        .help("Nightlight colour during the night (#rrggbb)"),
+                                )
+                                .arg(
+                                    Arg::with_name("shutdown-timeout")
+                                        .long("shutdown-timeout")
+                                        .takes_value(true)
+                                        .help("Idle time before turning off, in seconds"),
+                                )
+                                .arg(
+                                    Arg::with_name("bluetooth")
+                                        .long("bluetooth")
+                                        .takes_value(true)
+                                        .possible_values(["on", "off"])
+                                        .help("Enable or disable Bluetooth"),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    App::new("status")
+                        .about("Show the battery, volume, card and network of devices")
+                        .arg(
+                            Arg::with_name("device")
+                                .index(1)
+                                .conflicts_with("all")
+                                .help("Name or ID of the device"),
+                        )
+                        .arg(
+                            Arg::with_name("all")
+                                .long("all")
+                                .help("Show all the devices of the account"),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .help("Print the status as returned by the API"),
+                        ),
+                ),
+        )
         .subcommand(
             App::new("card")
                 .subcommand(App::new("list"))
                 .subcommand(App::new("info").arg(Arg::with_name("id").index(1)))
                 .subcommand(
-                    App::new("backup").arg(Arg::with_name("id").index(1)).arg(
-                        Arg::with_name("path")
-                            .long("path")
-                            .takes_value(true)
-                            .help("Path where to create the backup directory"),
-                    ),
+                    App::new("backup")
+                        .about("Back a card up with its audio and icons")
+                        .arg(Arg::with_name("id").index(1).required(true))
+                        .arg(
+                            Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("Path where to create the backup directory"),
+                        )
+                        .arg(
+                            Arg::with_name("zip")
+                                .long("zip")
+                                .help("Pack the backup into a zip archive"),
+                        ),
+                )
+                .subcommand(
+                    App::new("create")
+                        .about("Create a card from a folder of audio files")
+                        .arg(Arg::with_name("folder").index(1).required(true))
+                        .arg(
+                            Arg::with_name("title")
+                                .long("title")
+                                .takes_value(true)
+                                .help("Title of the card, the folder name by default"),
+                        )
+                        .arg(
+                            Arg::with_name("single-chapter")
+                                .long("single-chapter")
+                                .help("Put all files in one chapter instead of one chapter each"),
+                        )
+                        .arg(
+                            Arg::with_name("tag-titles")
+                                .long("tag-titles")
+                                .help("Take track titles from the file tags"),
+                        ),
+                )
+                .subcommand(
+                    App::new("edit")
+                        .about("Edit the chapters and tracks of a card, numbered from 1")
+                        .arg(Arg::with_name("id").index(1).required(true))
+                        .subcommand(
+                            App::new("rename-chapter")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("title").index(2).required(true)),
+                        )
+                        .subcommand(
+                            App::new("rename-track")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("track").index(2).required(true))
+                                .arg(Arg::with_name("title").index(3).required(true)),
+                        )
+                        .subcommand(
+                            App::new("move-chapter")
+                                .about("Move a chapter to another position")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("to").index(2).required(true)),
+                        )
+                        .subcommand(
+                            App::new("move-track")
+                                .about("Move a track to a chapter, at its end by default")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("track").index(2).required(true))
+                                .arg(Arg::with_name("to").index(3).required(true))
+                                .arg(Arg::with_name("position").index(4)),
+                        )
+                        .subcommand(
+                            App::new("remove-track")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("track").index(2).required(true)),
+                        )
+                        .subcommand(
+                            App::new("merge")
+                                .about("Merge a chapter with the following one")
+                                .arg(Arg::with_name("chapter").index(1).required(true)),
+                        )
+                        .subcommand(
+                            App::new("split")
+                                .about("Split a chapter before a track")
+                                .arg(Arg::with_name("chapter").index(1).required(true))
+                                .arg(Arg::with_name("track").index(2).required(true)),
+                        )
+                        .subcommand(
+                            App::new("renumber")
+                                .about("Renumber the keys and overlay labels of the card"),
+                        ),
+                )
+                .subcommand(
+                    App::new("restore")
+                        .about("Restore a card from a backup directory or zip archive")
+                        .arg(Arg::with_name("path").index(1).required(true))
+                        .arg(
+                            Arg::with_name("id")
+                                .long("id")
+                                .takes_value(true)
+                                .help("ID of an existing card to overwrite"),
+                        )
+                        .arg(
+                            Arg::with_name("reupload")
+                                .long("reupload")
+                                .help("Upload the audio again even if still available"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("apply")
+                .about("Create or update a card to match a YAML or TOML manifest")
+                .arg(Arg::with_name("manifest").index(1).required(true))
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only show the changes that would be made"),
+                ),
+        )
+        .subcommand(
+            App::new("mirror")
+                .about("Mirror all cards, only downloading the ones that changed")
+                .arg(Arg::with_name("path").index(1).required(true))
+                .arg(
+                    Arg::with_name("deleted")
+                        .long("deleted")
+                        .takes_value(true)
+                        .possible_values(["archive", "prune"])
+                        .default_value("archive")
+                        .help("What to do with cards deleted from the account"),
                 ),
         )
         .subcommand(App::new("upload").arg(Arg::with_name("path").index(1)))
+        .subcommand(
+            App::new("play")
+                .about("Start playing a card on a device")
+                .arg(Arg::with_name("device").index(1).required(true))
+                .arg(Arg::with_name("card").index(2).required(true))
+                .arg(
+                    Arg::with_name("chapter")
+                        .long("chapter")
+                        .takes_value(true)
+                        .help("Key of the chapter to start from"),
+                )
+                .arg(
+                    Arg::with_name("track")
+                        .long("track")
+                        .takes_value(true)
+                        .help("Key of the track to start from"),
+                )
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .help("Position to start from, e.g. 83 or 1:23"),
+                )
+                .arg(
+                    Arg::with_name("cut-off")
+                        .long("cut-off")
+                        .takes_value(true)
+                        .help("Position at which to stop playing"),
+                ),
+        )
+        .subcommand(
+            App::new("pause")
+                .about("Pause playback on a device")
+                .arg(Arg::with_name("device").index(1).required(true)),
+        )
+        .subcommand(
+            App::new("resume")
+                .about("Resume playback on a device")
+                .arg(Arg::with_name("device").index(1).required(true)),
+        )
+        .subcommand(
+            App::new("stop")
+                .about("Stop playback on a device")
+                .arg(Arg::with_name("device").index(1).required(true)),
+        )
+        .subcommand(
+            App::new("seek")
+                .about("Move to a position in the track playing on a device")
+                .arg(Arg::with_name("device").index(1).required(true))
+                .arg(
+                    Arg::with_name("position")
+                        .index(2)
+                        .required(true)
+                        .help("Position in the track, e.g. 83 or 1:23"),
+                ),
+        )
+        .subcommand(
+            App::new("simulate")
+                .about("Run a virtual player answering commands over MQTT")
+                .arg(Arg::with_name("device").index(1).required(true))
+                .arg(
+                    Arg::with_name("card")
+                        .long("card")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("JSON file of a card the player can play"),
+                ),
+        )
         .get_matches();
 
-    let entry = Entry::new("yoto-api", "oauth").unwrap();
-    let token = match entry.get_password() {
-        Ok(password) => serde_json::from_str(&password).ok(),
-        Err(_) => None,
+    let store = match token_store(&m) {
+        Ok(store) => store,
+        Err(err) => {
+            println!("ERROR: {}", err);
+            return;
+        }
+    };
+    let mut builder = api::Client::builder(CLIENT_ID).token_store(store);
+    if let Some(url) = m.value_of("api-url") {
+        builder = builder.base_url(url);
+    }
+    if let Some(url) = m.value_of("auth-url") {
+        builder = builder.auth_url(url);
+    }
+    if let Some(url) = m.value_of("token-url") {
+        builder = builder.token_url(url);
+    }
+    if let Some(timeout) = m.value_of("timeout") {
+        match timeout.parse::<u64>() {
+            Ok(secs) => builder = builder.timeout(Some(Duration::from_secs(secs))),
+            Err(_) => {
+                println!("Invalid timeout \"{}\"", timeout);
+                return;
+            }
+        }
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(err) => {
+            println!("ERROR: Failed to create client: {}", err);
+            return;
+        }
     };
-    let mut client = api::Client::new(CLIENT_ID, token);
 
     match m.subcommand() {
         Some(("login", _)) => {
-            match client.auth() {
-                Ok(()) => store_token(&entry, &client),
-                Err(_) => println!("ERROR: Failed to login"),
+            let result = client.start_login().and_then(|mut login| {
+                println!("User Code: {}", login.user_code);
+                println!("Verification: {}", login.verification_uri_complete);
+                client.wait_login(&mut login, |_, _| true)
+            });
+            match result {
+                Ok(()) => println!("Successfully logged in"),
+                Err(err) => println!("ERROR: Failed to login: {}", err),
             }
             return;
         }
         Some(("logout", _)) => {
-            let _ = entry.delete_credential();
+            if let Err(err) = client.logout() {
+                println!("ERROR: Failed to logout: {}", err);
+            }
+            return;
+        }
+        Some(("simulate", arg)) => {
+            if let Err(err) = simulate(&m, arg) {
+                println!("ERROR: Simulator failed: {}", err);
+            }
             return;
         }
         _ => (),
     }
 
     /* Other commands need authentication */
-    if client.token.is_none() {
+    if client.token().is_none() {
         println!("Please authenticate before using other commands");
         return;
     }
-    match client.refresh_token() {
-        api::RefreshStatus::AlreadyValid => (),
-        api::RefreshStatus::Refreshed => store_token(&entry, &client),
-        api::RefreshStatus::Failed => {
-            println!("Failed to refresh expired authentication token");
-            return;
-        }
-    };
 
     match m.subcommand() {
         Some(("devices", _)) => {
-            let devices = client.get_devices();
+            let devices = match client.get_devices() {
+                Ok(devices) => devices,
+                Err(err) => {
+                    println!("Error while retrieving devices: {}", err);
+                    return;
+                }
+            };
             if devices.is_empty() {
                 println!("No devices linked with this account.");
             } else {
@@ -85,9 +1254,40 @@ fn main() {
                 }
             }
         }
+        Some(("device", command)) => match command.subcommand() {
+            Some(("watch", arg)) => {
+                if let Err(err) = watch_device(&client, arg) {
+                    println!("ERROR: {}", err);
+                }
+            }
+            Some(("alarm", arg)) => {
+                if let Err(err) = device_alarms(&client, arg) {
+                    println!("ERROR: {}", err);
+                }
+            }
+            Some(("config", arg)) => {
+                if let Err(err) = device_config(&client, arg) {
+                    println!("ERROR: {}", err);
+                }
+            }
+            Some(("status", arg)) => {
+                if let Err(err) = device_status(&client, arg) {
+                    println!("ERROR: {}", err);
+                }
+            }
+            _ => {
+                println!("Invalid device command");
+            }
+        },
         Some(("card", command)) => match command.subcommand() {
-            Some(("list", arg)) => {
-                let cards = client.get_cards();
+            Some(("list", _)) => {
+                let cards = match client.get_cards() {
+                    Ok(cards) => cards,
+                    Err(err) => {
+                        println!("Error while retrieving cards: {}", err);
+                        return;
+                    }
+                };
                 if cards.is_empty() {
                     println!("No cards linked to this account.");
                 } else {
@@ -104,36 +1304,286 @@ fn main() {
                             println!("Card {}:", id);
                             println!("{:?}", card);
                         }
-                        Err(_) => {
-                            println!("Error while retrieving details for card \"{}\"", id);
+                        Err(err) => {
+                            println!(
+                                "Error while retrieving details for card \"{}\": {}",
+                                id, err
+                            );
                         }
                     }
                 }
             }
             Some(("backup", arg)) => {
-                if let Some(id) = arg.value_of("id") {
-                    match client.get_card(id, false) {
-                        Ok(card) => {
-                            println!("Card {}:", id);
-                            println!("{:?}", card);
+                let id = arg.value_of("id").unwrap();
+                let dest = Path::new(arg.value_of("path").unwrap_or("."));
+                match backup::backup_card(&client, id, dest, arg.is_present("zip")) {
+                    Ok(backup) => {
+                        for file in backup.skipped.iter() {
+                            println!("Skipped {} ({})", file.path, file.url);
                         }
-                        Err(_) => {
-                            println!("Error while retrieving details for card \"{}\"", id);
+                        println!(
+                            "Backed up {} files to {}",
+                            backup.files.len(),
+                            backup.path.display()
+                        );
+                    }
+                    Err(err) => println!("Error while backing up card \"{}\": {}", id, err),
+                }
+            }
+            Some(("create", arg)) => {
+                let folder = arg.value_of("folder").unwrap();
+                let options = PlaylistOptions {
+                    title: arg.value_of("title").map(str::to_string),
+                    single_chapter: arg.is_present("single-chapter"),
+                    tag_titles: arg.is_present("tag-titles"),
+                };
+                let result =
+                    playlist::card_from_folder(&client, Path::new(folder), &options, |path| {
+                        println!("Uploading {}", path.display())
+                    })
+                    .and_then(|card| client.create_card(&card));
+                match result {
+                    Ok(card) => println!("Created card {}: {}", card.card_id, card.title),
+                    Err(err) => println!("Error while creating card from \"{}\": {}", folder, err),
+                }
+            }
+            Some(("edit", arg)) => {
+                let id = arg.value_of("id").unwrap();
+                match edit_card(&client, id, arg) {
+                    Ok(card) => println!("Updated card {}: {}", card.card_id, card.title),
+                    Err(err) => println!("Error while editing card \"{}\": {}", id, err),
+                }
+            }
+            Some(("restore", arg)) => {
+                let path = arg.value_of("path").unwrap();
+                let result = backup::restore_card(
+                    &client,
+                    Path::new(path),
+                    arg.value_of("id"),
+                    arg.is_present("reupload"),
+                );
+                match result {
+                    Ok(restore) => {
+                        for file in restore.uploaded.iter() {
+                            println!("  Uploaded {}", file);
                         }
+                        for file in restore.reused.iter() {
+                            println!("  Reused {}", file);
+                        }
+                        println!(
+                            "Restored card {} ({} uploaded, {} reused)",
+                            restore.card.card_id,
+                            restore.uploaded.len(),
+                            restore.reused.len()
+                        );
                     }
+                    Err(err) => println!("Error while restoring \"{}\": {}", path, err),
                 }
             }
             _ => {
                 println!("Invalid card command");
-                return;
             }
         },
+        Some(("apply", arg)) => {
+            let path = arg.value_of("manifest").unwrap();
+            let plan = match manifest::plan(&client, Path::new(path)) {
+                Ok(plan) => plan,
+                Err(err) => {
+                    println!("Error while reading \"{}\": {}", path, err);
+                    return;
+                }
+            };
+            if plan.is_empty() {
+                println!("Card is up to date");
+                return;
+            }
+            println!("Changes:");
+            for change in plan.changes.iter() {
+                println!("  {}", change);
+            }
+            if arg.is_present("dry-run") {
+                return;
+            }
+            match plan.apply(&client, |path| println!("Uploading {}", path.display())) {
+                Ok(card) => println!("Applied to card {}", card.card_id),
+                Err(err) => println!("Error while applying \"{}\": {}", path, err),
+            }
+        }
+        Some(("mirror", arg)) => {
+            let path = arg.value_of("path").unwrap();
+            let deleted = match arg.value_of("deleted") {
+                Some("prune") => Deleted::Prune,
+                _ => Deleted::Archive,
+            };
+            match mirror::mirror(&client, Path::new(path), deleted) {
+                Ok(mirror) => {
+                    for (id, err) in mirror.failed.iter() {
+                        println!("ERROR: Failed to mirror card {}: {}", id, err);
+                    }
+                    println!(
+                        "Mirrored cards to {}: {} downloaded, {} unchanged, {} deleted, {} failed",
+                        path,
+                        mirror.downloaded.len(),
+                        mirror.unchanged.len(),
+                        mirror.deleted.len(),
+                        mirror.failed.len()
+                    )
+                }
+                Err(err) => println!("Error while mirroring cards to \"{}\": {}", path, err),
+            }
+        }
         Some(("upload", arg)) => {
             if let Some(path) = arg.value_of("path") {
-                let uuid = client.upload_audio_file(Path::new(path)).unwrap();
-                println!("Upload SHA256: {}", uuid);
+                match client.upload_audio_file(Path::new(path)) {
+                    Ok(uuid) => println!("Upload SHA256: {}", uuid),
+                    Err(err) => println!("Error while uploading \"{}\": {}", path, err),
+                }
+            }
+        }
+        Some(("play", arg)) => {
+            let device = arg.value_of("device").unwrap();
+            let mut target = CardTarget::new(arg.value_of("card").unwrap());
+            if let Some(chapter) = arg.value_of("chapter") {
+                target = target.chapter(chapter);
+            }
+            if let Some(track) = arg.value_of("track") {
+                target = target.track(track);
+            }
+            let positions = (
+                arg.value_of("at").map(parse_position).transpose(),
+                arg.value_of("cut-off").map(parse_position).transpose(),
+            );
+            match positions {
+                (Ok(at), Ok(cut_off)) => {
+                    if let Some(at) = at {
+                        target = target.seconds_in(at);
+                    }
+                    if let Some(cut_off) = cut_off {
+                        target = target.cut_off(cut_off);
+                    }
+                    if let Err(err) = send_command(&client, &m, device, Command::Start(target)) {
+                        println!("ERROR: Failed to start playback: {}", err);
+                    }
+                }
+                (Err(err), _) | (_, Err(err)) => println!("ERROR: {}", err),
+            }
+        }
+        Some(("seek", arg)) => {
+            let device = arg.value_of("device").unwrap();
+            let result = parse_position(arg.value_of("position").unwrap())
+                .and_then(|position| seek(&client, &m, device, position));
+            if let Err(err) = result {
+                println!("ERROR: Failed to seek: {}", err);
+            }
+        }
+        Some((action @ ("pause" | "resume" | "stop"), arg)) => {
+            let device = arg.value_of("device").unwrap();
+            let command = match action {
+                "pause" => Command::Pause,
+                "resume" => Command::Resume,
+                _ => Command::Stop,
+            };
+            if let Err(err) = send_command(&client, &m, device, command) {
+                println!("ERROR: Failed to {} playback: {}", action, err);
             }
         }
         _ => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn positions() {
+        assert_eq!(parse_position("83"), Ok(83));
+        assert_eq!(parse_position("1:23"), Ok(83));
+        assert_eq!(parse_position("1:02:03"), Ok(3723));
+        for invalid in ["", "1:", "a:23", "-1", "99999999999"] {
+            assert!(parse_position(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    fn device(id: &str, name: &str) -> Device {
+        serde_json::from_value(json!({
+            "deviceId": id,
+            "name": name,
+            "description": "",
+            "online": true
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn devices_by_name_or_id() {
+        let devices = || {
+            vec![
+                device("y1", "Kitchen"),
+                device("y2", "Bedroom"),
+                device("y3", "bedroom"),
+                device("y4", "y1"),
+            ]
+        };
+        assert_eq!(match_device(devices(), "y2").unwrap().id, "y2");
+        assert_eq!(match_device(devices(), "kitchen").unwrap().id, "y1");
+        /* Identifiers take precedence over names */
+        assert_eq!(match_device(devices(), "y1").unwrap().id, "y1");
+        assert!(match_device(devices(), "Bedroom").is_err());
+        assert!(match_device(devices(), "Garage").is_err());
+    }
+
+    #[test]
+    fn status_json() {
+        let sample: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/data/device_status.json")).unwrap();
+        let status = |battery: u32| {
+            let mut sample = sample.clone();
+            sample["batteryLevelPercentage"] = json!(battery);
+            serde_json::from_value::<DeviceStatus>(sample).unwrap()
+        };
+        let statuses = vec![
+            (device("y1", "Kitchen"), status(87)),
+            (device("y2", "Bedroom"), status(50)),
+        ];
+
+        let single = statuses_json(&statuses[..1], false).unwrap();
+        assert_eq!(single["batteryLevelPercentage"], 87);
+        let all = statuses_json(&statuses, true).unwrap();
+        assert_eq!(all[0]["deviceId"], "y1");
+        assert_eq!(all[0]["name"], "Kitchen");
+        assert_eq!(all[0]["status"], single);
+        assert_eq!(all[1]["deviceId"], "y2");
+        assert_eq!(all[1]["name"], "Bedroom");
+        assert_eq!(all[1]["status"]["batteryLevelPercentage"], 50);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1_600), "1.6 KB");
+        assert_eq!(format_size(29_460_480_000), "29.5 GB");
+        assert_eq!(format_size(u64::MAX), "18446744.1 TB");
+    }
+
+    #[test]
+    fn seek_in_playing_track() {
+        let event = Event {
+            card_id: Some("5WsQ9".to_string()),
+            chapter_key: Some("02".to_string()),
+            track_key: Some("02-1".to_string()),
+            track_length: Some(120),
+            ..Event::default()
+        };
+        assert_eq!(
+            seek_target(&event, 83),
+            Ok(CardTarget::new("5WsQ9")
+                .chapter("02")
+                .track("02-1")
+                .seconds_in(83))
+        );
+        assert!(seek_target(&event, 120).is_err());
+        assert!(seek_target(&Event::default(), 83).is_err());
+    }
+}
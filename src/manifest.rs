@@ -0,0 +1,1053 @@
+//! Declarative card manifests, kept as YAML or TOML files and synchronized
+//! with the live cards.
+//!
+//! ```yaml
+//! title: Bedtime stories
+//! author: Grandma
+//! config:
+//!   autoadvance: true
+//! chapters:
+//!   - title: The Fox
+//!     file: audio/fox.mp3
+//!   - title: The Owl
+//!     icon: "yoto:#aBcD"
+//!     tracks:
+//!       - file: audio/owl-1.mp3
+//!       - title: Part 2
+//!         url: "yoto:#5d8e..."
+//! ```
+//!
+//! Files are relative to the manifest. A lock file next to it
+//! (`<manifest>.lock`) records the ID of the card and the audio already
+//! uploaded, so that only new or modified files are uploaded again.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::api::{Client, Transcoded, TranscodedInfo};
+use crate::error::{Error, Result};
+use crate::model::{media_url, Card, Chapter, Icon, PlaybackType, Track};
+use crate::playlist::file_title;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// ID of the card, recorded in the lock file once created if not set.
+    pub id: Option<String>,
+    pub title: String,
+    pub author: Option<String>,
+    pub category: Option<String>,
+    pub description: Option<String>,
+    pub playback_type: Option<PlaybackType>,
+    pub config: ManifestConfig,
+    pub chapters: Vec<ManifestChapter>,
+}
+
+/// Content configuration; unset fields are left as they are on the card.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestConfig {
+    pub autoadvance: Option<bool>,
+    pub resume_timeout: Option<u64>,
+    pub system_activity: Option<bool>,
+    pub track_number_overlay_timeout: Option<u64>,
+}
+
+/// Chapter, either with a list of tracks or with a single track given by
+/// `file` or `url`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestChapter {
+    pub title: String,
+    pub icon: Option<String>,
+    pub file: Option<PathBuf>,
+    pub url: Option<String>,
+    pub tracks: Vec<ManifestTrack>,
+}
+
+/// Track playing either a local `file` or an already uploaded `url`, given as
+/// a `yoto:#` reference. A `url` must be audio of the card, or audio uploaded
+/// from a file of the manifest, since the properties of the audio are needed
+/// to add it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestTrack {
+    /// Title of the track, derived from the file name if not set.
+    pub title: Option<String>,
+    pub icon: Option<String>,
+    pub file: Option<PathBuf>,
+    pub url: Option<String>,
+}
+
+/// State of a manifest, stored next to it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Lock {
+    pub card_id: Option<String>,
+    /// Uploaded files, by path relative to the manifest.
+    pub files: BTreeMap<String, LockedFile>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockedFile {
+    pub size: u64,
+    /// Modification time, in seconds since the UNIX epoch.
+    pub modified: u64,
+    pub sha256: String,
+    pub info: TranscodedInfo,
+}
+
+/// Change needed to bring a card in line with its manifest. Chapters and
+/// tracks are numbered from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    CreateCard {
+        title: String,
+    },
+    SetTitle {
+        from: String,
+        to: String,
+    },
+    SetField {
+        field: String,
+        from: String,
+        to: String,
+    },
+    AddChapter {
+        chapter: usize,
+        title: String,
+    },
+    RemoveChapter {
+        chapter: usize,
+        title: String,
+    },
+    RetitleChapter {
+        chapter: usize,
+        from: String,
+        to: String,
+    },
+    SetChapterIcon {
+        chapter: usize,
+        from: String,
+        to: String,
+    },
+    AddTrack {
+        chapter: usize,
+        title: String,
+        upload: Option<PathBuf>,
+    },
+    RemoveTrack {
+        chapter: usize,
+        title: String,
+    },
+    MoveTrack {
+        title: String,
+        from: (usize, usize),
+        to: (usize, usize),
+    },
+    RetitleTrack {
+        chapter: usize,
+        from: String,
+        to: String,
+    },
+    SetTrackIcon {
+        chapter: usize,
+        title: String,
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Audio {
+    /// Already uploaded audio, with its properties if known.
+    Uploaded(String, Option<TranscodedInfo>),
+    /// Local file to upload, with its path relative to the manifest.
+    Pending(String),
+}
+
+#[derive(Clone, Debug)]
+struct DesiredTrack {
+    title: String,
+    icon: Option<String>,
+    audio: Audio,
+}
+
+#[derive(Clone, Debug)]
+struct DesiredChapter {
+    title: String,
+    icon: Option<String>,
+    tracks: Vec<DesiredTrack>,
+}
+
+/// Changes between a manifest and its live card, applied with
+/// [`Plan::apply`].
+#[derive(Debug)]
+pub struct Plan {
+    /// ID of the card, if it exists.
+    pub card_id: Option<String>,
+    pub changes: Vec<Change>,
+    manifest: Manifest,
+    chapters: Vec<DesiredChapter>,
+    dir: PathBuf,
+    lock_path: PathBuf,
+    lock: Lock,
+    live: Option<Card>,
+}
+
+fn pos(chapter: usize, track: usize) -> String {
+    format!("{}.{}", chapter, track)
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::CreateCard { title } => write!(f, "+ card \"{}\"", title),
+            Change::SetTitle { from, to } => write!(f, "~ title \"{}\" -> \"{}\"", from, to),
+            Change::SetField { field, from, to } => {
+                write!(f, "~ {} \"{}\" -> \"{}\"", field, from, to)
+            }
+            Change::AddChapter { chapter, title } => {
+                write!(f, "+ chapter {} \"{}\"", chapter, title)
+            }
+            Change::RemoveChapter { chapter, title } => {
+                write!(f, "- chapter {} \"{}\"", chapter, title)
+            }
+            Change::RetitleChapter { chapter, from, to } => {
+                write!(f, "~ chapter {} \"{}\" -> \"{}\"", chapter, from, to)
+            }
+            Change::SetChapterIcon { chapter, from, to } => {
+                write!(f, "~ chapter {} icon \"{}\" -> \"{}\"", chapter, from, to)
+            }
+            Change::AddTrack {
+                chapter,
+                title,
+                upload,
+            } => {
+                write!(f, "+ track \"{}\" in chapter {}", title, chapter)?;
+                match upload {
+                    Some(path) => write!(f, " (upload {})", path.display()),
+                    None => Ok(()),
+                }
+            }
+            Change::RemoveTrack { chapter, title } => {
+                write!(f, "- track \"{}\" from chapter {}", title, chapter)
+            }
+            Change::MoveTrack { title, from, to } => write!(
+                f,
+                "~ track \"{}\" {} -> {}",
+                title,
+                pos(from.0, from.1),
+                pos(to.0, to.1)
+            ),
+            Change::RetitleTrack { chapter, from, to } => write!(
+                f,
+                "~ track \"{}\" -> \"{}\" in chapter {}",
+                from, to, chapter
+            ),
+            Change::SetTrackIcon {
+                chapter,
+                title,
+                from,
+                to,
+            } => write!(
+                f,
+                "~ track \"{}\" icon \"{}\" -> \"{}\" in chapter {}",
+                title, from, to, chapter
+            ),
+        }
+    }
+}
+
+impl Manifest {
+    /// Loads a manifest, in YAML or TOML depending on its extension.
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let data = fs::read_to_string(path)?;
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "yaml" | "yml" => {
+                serde_yaml::from_str(&data).map_err(|err| Error::Manifest(err.to_string()))
+            }
+            "toml" => toml::from_str(&data).map_err(|err| Error::Manifest(err.to_string())),
+            _ => Err(Error::UnsupportedFormat(ext)),
+        }
+    }
+
+    /// Tracks of every chapter, expanding single track chapters.
+    fn tracks(&self) -> Result<Vec<Vec<ManifestTrack>>> {
+        let mut chapters = Vec::new();
+        for chapter in self.chapters.iter() {
+            let tracks = match (&chapter.file, &chapter.url) {
+                (None, None) if !chapter.tracks.is_empty() => chapter.tracks.clone(),
+                (Some(_), None) | (None, Some(_)) if chapter.tracks.is_empty() => {
+                    vec![ManifestTrack {
+                        title: Some(chapter.title.clone()),
+                        icon: chapter.icon.clone(),
+                        file: chapter.file.clone(),
+                        url: chapter.url.clone(),
+                    }]
+                }
+                _ => {
+                    return Err(Error::Manifest(format!(
+                        "chapter \"{}\" needs either tracks, a file or a url",
+                        chapter.title
+                    )))
+                }
+            };
+            for track in tracks.iter() {
+                if track.file.is_some() == track.url.is_some() {
+                    return Err(Error::Manifest(format!(
+                        "a track of chapter \"{}\" needs either a file or a url",
+                        chapter.title
+                    )));
+                }
+                if let Some(url) = track.url.as_ref().filter(|url| !url.starts_with("yoto:#")) {
+                    return Err(Error::Manifest(format!(
+                        "url \"{}\" of chapter \"{}\" is not uploaded audio (yoto:#...)",
+                        url, chapter.title
+                    )));
+                }
+            }
+            chapters.push(tracks);
+        }
+        Ok(chapters)
+    }
+}
+
+impl Lock {
+    pub fn path(manifest: &Path) -> PathBuf {
+        let mut path = manifest.as_os_str().to_owned();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Lock> {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Lock::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Returns the uploaded audio of a file, unless it changed since.
+    fn uploaded(&self, dir: &Path, file: &str) -> Result<Option<&LockedFile>> {
+        let (size, modified) = file_stamp(&dir.join(file))?;
+        Ok(self
+            .files
+            .get(file)
+            .filter(|locked| locked.size == size && locked.modified == modified))
+    }
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), modified))
+}
+
+fn icon_ref(icon: &Option<Icon>) -> String {
+    icon.as_ref()
+        .and_then(|icon| icon.small.clone())
+        .unwrap_or_default()
+}
+
+fn unknown_audio(url: &str) -> Error {
+    Error::Manifest(format!(
+        "url \"{}\" is neither on the card nor uploaded from a file of the manifest",
+        url
+    ))
+}
+
+fn text<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(serde_json::Value::Null) | Err(_) => String::new(),
+        Ok(value) => value.to_string(),
+    }
+}
+
+/// Records a change of a managed field, i.e. one set in the manifest.
+fn diff_field<T: Serialize + PartialEq>(
+    changes: &mut Vec<Change>,
+    field: &str,
+    live: &T,
+    desired: &Option<T>,
+) {
+    if let Some(desired) = desired {
+        if desired != live {
+            changes.push(Change::SetField {
+                field: field.to_string(),
+                from: text(live),
+                to: text(desired),
+            });
+        }
+    }
+}
+
+/// Compares a manifest with the live card, if any.
+fn diff(manifest: &Manifest, chapters: &[DesiredChapter], live: Option<&Card>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let Some(live) = live else {
+        changes.push(Change::CreateCard {
+            title: manifest.title.clone(),
+        });
+        for (i, chapter) in chapters.iter().enumerate() {
+            changes.push(Change::AddChapter {
+                chapter: i + 1,
+                title: chapter.title.clone(),
+            });
+            for track in chapter.tracks.iter() {
+                changes.push(add_track(i, track));
+            }
+        }
+        return changes;
+    };
+
+    if live.title != manifest.title {
+        changes.push(Change::SetTitle {
+            from: live.title.clone(),
+            to: manifest.title.clone(),
+        });
+    }
+    let metadata = &live.metadata;
+    diff_field(&mut changes, "author", &metadata.author, &manifest.author);
+    diff_field(
+        &mut changes,
+        "category",
+        &metadata.category,
+        &manifest.category,
+    );
+    let description = &manifest.description;
+    diff_field(
+        &mut changes,
+        "description",
+        &metadata.description,
+        description,
+    );
+    let content = &live.content;
+    let playback_type = &manifest.playback_type;
+    diff_field(
+        &mut changes,
+        "playback_type",
+        &content.playback_type,
+        playback_type,
+    );
+    let (config, desired) = (&content.config, &manifest.config);
+    let fields = [
+        ("autoadvance", config.autoadvance, desired.autoadvance),
+        (
+            "system_activity",
+            config.system_activity,
+            desired.system_activity,
+        ),
+    ];
+    for (field, live, desired) in fields {
+        diff_field(&mut changes, field, &live, &desired.map(Some));
+    }
+    let fields = [
+        (
+            "resume_timeout",
+            config.resume_timeout,
+            desired.resume_timeout,
+        ),
+        (
+            "track_number_overlay_timeout",
+            config.track_number_overlay_timeout,
+            desired.track_number_overlay_timeout,
+        ),
+    ];
+    for (field, live, desired) in fields {
+        diff_field(&mut changes, field, &live, &desired.map(Some));
+    }
+
+    for (i, chapter) in chapters.iter().enumerate() {
+        match content.chapters.get(i) {
+            None => changes.push(Change::AddChapter {
+                chapter: i + 1,
+                title: chapter.title.clone(),
+            }),
+            Some(current) => {
+                if current.title != chapter.title {
+                    changes.push(Change::RetitleChapter {
+                        chapter: i + 1,
+                        from: current.title.clone(),
+                        to: chapter.title.clone(),
+                    });
+                }
+                let icon = chapter.icon.clone().unwrap_or_default();
+                if icon_ref(&current.display) != icon {
+                    changes.push(Change::SetChapterIcon {
+                        chapter: i + 1,
+                        from: icon_ref(&current.display),
+                        to: icon,
+                    });
+                }
+            }
+        }
+    }
+    for (i, chapter) in content.chapters.iter().enumerate().skip(chapters.len()) {
+        changes.push(Change::RemoveChapter {
+            chapter: i + 1,
+            title: chapter.title.clone(),
+        });
+    }
+
+    /* Tracks are identified by their audio */
+    let mut remaining: Vec<Option<(usize, usize, &Track)>> = content
+        .chapters
+        .iter()
+        .enumerate()
+        .flat_map(|(i, chapter)| {
+            chapter
+                .tracks
+                .iter()
+                .enumerate()
+                .map(move |(j, track)| Some((i, j, track)))
+        })
+        .collect();
+    for (i, chapter) in chapters.iter().enumerate() {
+        for (j, track) in chapter.tracks.iter().enumerate() {
+            let found = match &track.audio {
+                Audio::Uploaded(url, _) => remaining
+                    .iter_mut()
+                    .find(|entry| entry.is_some_and(|(_, _, live)| live.track_url == *url))
+                    .and_then(Option::take),
+                Audio::Pending(_) => None,
+            };
+            let Some((ci, ti, current)) = found else {
+                changes.push(add_track(i, track));
+                continue;
+            };
+            if (ci, ti) != (i, j) {
+                changes.push(Change::MoveTrack {
+                    title: track.title.clone(),
+                    from: (ci + 1, ti + 1),
+                    to: (i + 1, j + 1),
+                });
+            }
+            if current.title != track.title {
+                changes.push(Change::RetitleTrack {
+                    chapter: i + 1,
+                    from: current.title.clone(),
+                    to: track.title.clone(),
+                });
+            }
+            let icon = track.icon.clone().unwrap_or_default();
+            if icon_ref(&current.icon) != icon {
+                changes.push(Change::SetTrackIcon {
+                    chapter: i + 1,
+                    title: track.title.clone(),
+                    from: icon_ref(&current.icon),
+                    to: icon,
+                });
+            }
+        }
+    }
+    for (i, _, track) in remaining.into_iter().flatten() {
+        changes.push(Change::RemoveTrack {
+            chapter: i + 1,
+            title: track.title.clone(),
+        });
+    }
+    changes
+}
+
+fn add_track(chapter: usize, track: &DesiredTrack) -> Change {
+    Change::AddTrack {
+        chapter: chapter + 1,
+        title: track.title.clone(),
+        upload: match &track.audio {
+            Audio::Pending(file) => Some(PathBuf::from(file)),
+            Audio::Uploaded(..) => None,
+        },
+    }
+}
+
+/// Compares a manifest with its live card.
+pub fn plan(client: &Client, path: &Path) -> Result<Plan> {
+    let manifest = Manifest::load(path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let lock_path = Lock::path(path);
+    let lock = Lock::load(&lock_path)?;
+
+    let mut chapters = Vec::new();
+    for (chapter, tracks) in manifest.chapters.iter().zip(manifest.tracks()?) {
+        let mut desired = DesiredChapter {
+            title: chapter.title.clone(),
+            icon: chapter.icon.clone(),
+            tracks: Vec::new(),
+        };
+        for track in tracks {
+            let audio = match (&track.file, track.url) {
+                (Some(file), _) => {
+                    let file = file.to_string_lossy().to_string();
+                    match lock.uploaded(&dir, &file)? {
+                        Some(locked) => {
+                            Audio::Uploaded(media_url(&locked.sha256), Some(locked.info.clone()))
+                        }
+                        None => Audio::Pending(file),
+                    }
+                }
+                (None, Some(url)) => {
+                    let info = lock
+                        .files
+                        .values()
+                        .find(|locked| media_url(&locked.sha256) == url)
+                        .map(|locked| locked.info.clone());
+                    Audio::Uploaded(url, info)
+                }
+                (None, None) => unreachable!("checked by Manifest::tracks"),
+            };
+            let title = match (track.title, &track.file) {
+                (Some(title), _) => title,
+                (None, Some(file)) => file_title(file),
+                (None, None) => String::new(),
+            };
+            desired.tracks.push(DesiredTrack {
+                title,
+                icon: track.icon,
+                audio,
+            });
+        }
+        chapters.push(desired);
+    }
+
+    let card_id = manifest.id.clone().or_else(|| lock.card_id.clone());
+    let live = match &card_id {
+        Some(id) => match client.get_card(id, false) {
+            Ok(card) => Some(card),
+            /* Deleted since: it will be created again */
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err),
+        },
+        None => None,
+    };
+    Ok(Plan {
+        card_id: live.as_ref().map(|card| card.card_id.clone()),
+        changes: diff(&manifest, &chapters, live.as_ref()),
+        manifest,
+        chapters,
+        dir,
+        lock_path,
+        lock,
+        live,
+    })
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Uploads the new audio and creates or updates the card.
+    ///
+    /// `on_upload` is called before each file is uploaded.
+    pub fn apply<F>(mut self, client: &Client, mut on_upload: F) -> Result<Card>
+    where
+        F: FnMut(&Path),
+    {
+        let mut card = self.live.take().unwrap_or_else(|| Card::new(""));
+        let mut live_chapters: Vec<Chapter> = card.content.chapters.drain(..).collect();
+        let mut live_tracks: Vec<Option<Track>> = live_chapters
+            .iter_mut()
+            .flat_map(|chapter| chapter.tracks.drain(..))
+            .map(Some)
+            .collect();
+        let mut live_chapters = live_chapters.into_iter();
+
+        /* Fail before uploading anything if audio cannot be added */
+        for track in self
+            .chapters
+            .iter()
+            .flat_map(|chapter| chapter.tracks.iter())
+        {
+            if let Audio::Uploaded(url, None) = &track.audio {
+                if !live_tracks
+                    .iter()
+                    .flatten()
+                    .any(|live| live.track_url == *url)
+                {
+                    return Err(unknown_audio(url));
+                }
+            }
+        }
+
+        let manifest = &self.manifest;
+        card.title = manifest.title.clone();
+        let metadata = &mut card.metadata;
+        for (field, value) in [
+            (&mut metadata.author, &manifest.author),
+            (&mut metadata.category, &manifest.category),
+            (&mut metadata.description, &manifest.description),
+        ] {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+        if let Some(playback_type) = &manifest.playback_type {
+            card.content.playback_type = playback_type.clone();
+        }
+        let (config, desired) = (&mut card.content.config, &manifest.config);
+        config.autoadvance = desired.autoadvance.or(config.autoadvance);
+        config.resume_timeout = desired.resume_timeout.or(config.resume_timeout);
+        config.system_activity = desired.system_activity.or(config.system_activity);
+        config.track_number_overlay_timeout = desired
+            .track_number_overlay_timeout
+            .or(config.track_number_overlay_timeout);
+
+        let mut number = 0;
+        for (i, desired) in self.chapters.iter().enumerate() {
+            let key = format!("{:02}", i + 1);
+            /* Chapters are matched by position, as in the plan */
+            let chapter = match live_chapters.next() {
+                Some(mut chapter) => {
+                    chapter.key = key;
+                    chapter.title = desired.title.clone();
+                    chapter.duration = None;
+                    chapter.file_size = None;
+                    chapter
+                }
+                None => Chapter::new(&key, &desired.title),
+            };
+            let mut chapter = chapter.overlay_label(&(i + 1).to_string());
+            let icon = desired.icon.clone().unwrap_or_default();
+            if icon_ref(&chapter.display) != icon {
+                chapter.display = desired.icon.as_deref().map(Icon::new);
+            }
+            for track in desired.tracks.iter() {
+                number += 1;
+                let key = format!("{:02}", number);
+                let transcoded = match &track.audio {
+                    Audio::Uploaded(url, info) => {
+                        let live = live_tracks
+                            .iter_mut()
+                            .find(|live| live.as_ref().is_some_and(|t| t.track_url == *url))
+                            .and_then(Option::take);
+                        if let Some(mut live) = live {
+                            live.key = key.clone();
+                            live.title = track.title.clone();
+                            /* Keep the extra fields of unchanged icons */
+                            if icon_ref(&live.icon) != track.icon.clone().unwrap_or_default() {
+                                live.icon = track.icon.as_deref().map(Icon::new);
+                            }
+                            chapter = chapter.track(live.overlay_label(&number.to_string()));
+                            continue;
+                        }
+                        Transcoded {
+                            sha256: url.trim_start_matches("yoto:#").to_string(),
+                            info: info.clone().ok_or_else(|| unknown_audio(url))?,
+                        }
+                    }
+                    Audio::Pending(file) => {
+                        let path = self.dir.join(file);
+                        on_upload(&path);
+                        let transcoded = client.upload_audio(&path)?;
+                        let (size, modified) = file_stamp(&path)?;
+                        self.lock.files.insert(
+                            file.clone(),
+                            LockedFile {
+                                size,
+                                modified,
+                                sha256: transcoded.sha256.clone(),
+                                info: transcoded.info.clone(),
+                            },
+                        );
+                        /* Keep track of the uploads even if a later one fails */
+                        self.lock.save(&self.lock_path)?;
+                        transcoded
+                    }
+                };
+                let mut new = transcoded
+                    .track(&key, &track.title)
+                    .overlay_label(&number.to_string());
+                if let Some(icon) = &track.icon {
+                    new = new.icon(icon);
+                }
+                chapter = chapter.track(new);
+            }
+            card.content.chapters.push(chapter);
+        }
+
+        let card = match self.card_id {
+            Some(_) => client.update_card(&card)?,
+            None => client.create_card(&card)?,
+        };
+        self.lock.card_id = Some(card.card_id.clone());
+        self.lock.save(&self.lock_path)?;
+        Ok(card)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client, serve};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    fn desired(manifest: &Manifest) -> Vec<DesiredChapter> {
+        manifest
+            .chapters
+            .iter()
+            .zip(manifest.tracks().unwrap())
+            .map(|(chapter, tracks)| DesiredChapter {
+                title: chapter.title.clone(),
+                icon: chapter.icon.clone(),
+                tracks: tracks
+                    .into_iter()
+                    .map(|track| DesiredTrack {
+                        title: track.title.unwrap_or_default(),
+                        icon: track.icon,
+                        audio: match track.url {
+                            Some(url) => Audio::Uploaded(url, None),
+                            None => Audio::Pending(track.file.unwrap().display().to_string()),
+                        },
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_changes() {
+        let manifest: Manifest = serde_yaml::from_str(
+            r#"
+title: Stories
+author: Grandma
+config:
+  autoadvance: true
+chapters:
+  - title: Owl
+    tracks:
+      - title: Owl 2
+        url: "yoto:#b"
+      - title: Owl 1
+        url: "yoto:#a"
+  - title: Cat
+    file: cat.mp3
+"#,
+        )
+        .unwrap();
+        let live: Card = serde_json::from_value(json!({
+            "cardId": "5WsQ9",
+            "title": "Stories",
+            "metadata": { "author": "", "category": "", "description": "" },
+            "content": {
+                "chapters": [{
+                    "key": "01",
+                    "title": "Owl",
+                    "tracks": [{
+                        "key": "01", "title": "Owl 1", "trackUrl": "yoto:#a",
+                        "type": "audio", "format": "aac", "overlayLabel": "1",
+                        "duration": 10, "fileSize": 100
+                    }, {
+                        "key": "02", "title": "Owl two", "trackUrl": "yoto:#b",
+                        "type": "audio", "format": "aac", "overlayLabel": "2",
+                        "duration": 10, "fileSize": 100
+                    }, {
+                        "key": "03", "title": "Fox", "trackUrl": "yoto:#c",
+                        "type": "audio", "format": "aac", "overlayLabel": "3",
+                        "duration": 10, "fileSize": 100
+                    }]
+                }]
+            }
+        }))
+        .unwrap();
+
+        let changes = diff(&manifest, &desired(&manifest), Some(&live));
+        let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            changes,
+            vec![
+                "~ author \"\" -> \"Grandma\"",
+                "~ autoadvance \"\" -> \"true\"",
+                "+ chapter 2 \"Cat\"",
+                "~ track \"Owl 2\" 1.2 -> 1.1",
+                "~ track \"Owl two\" -> \"Owl 2\" in chapter 1",
+                "~ track \"Owl 1\" 1.1 -> 1.2",
+                "+ track \"Cat\" in chapter 2 (upload cat.mp3)",
+                "- track \"Fox\" from chapter 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_chapters() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+title = "Stories"
+
+[[chapters]]
+title = "Owl"
+file = "owl.mp3"
+url = "yoto:#a"
+"#,
+        )
+        .unwrap();
+        assert!(matches!(manifest.tracks(), Err(Error::Manifest(_))));
+    }
+
+    #[test]
+    fn invalid_urls() {
+        let manifest: Manifest = serde_yaml::from_str(
+            r#"
+title: Stories
+chapters:
+  - title: Radio
+    url: "https://example.com/radio.mp3"
+"#,
+        )
+        .unwrap();
+        assert!(matches!(manifest.tracks(), Err(Error::Manifest(_))));
+    }
+
+    /// Serves a card with a single chapter and track, returning the URL and
+    /// the card saved by the client.
+    fn live_card_server() -> (String, Arc<Mutex<Value>>) {
+        let saved = Arc::new(Mutex::new(Value::Null));
+        let url = {
+            let saved = saved.clone();
+            serve(move |method, path, body| {
+                let response = match (method, path.split('?').next().unwrap()) {
+                    ("GET", "/content/5WsQ9") => json!({ "card": {
+                        "cardId": "5WsQ9",
+                        "title": "Stories",
+                        "content": {
+                            "chapters": [{
+                                "key": "01",
+                                "title": "Owl",
+                                "availableFrom": "2025-12-01",
+                                "display": { "icon16x16": "yoto:#owl" },
+                                "duration": 20,
+                                "fileSize": 200,
+                                "tracks": [{
+                                    "key": "01", "title": "Owl 1", "trackUrl": "yoto:#a",
+                                    "type": "audio", "format": "aac", "overlayLabel": "1",
+                                    "duration": 20, "fileSize": 200,
+                                    "display": { "icon16x16": "yoto:#owl", "iconUrl16x16": "https://owl" }
+                                }]
+                            }]
+                        }
+                    }}),
+                    ("POST", "/content") => {
+                        *saved.lock().unwrap() = serde_json::from_slice(body).unwrap();
+                        json!({ "card": *saved.lock().unwrap() })
+                    }
+                    _ => panic!("unexpected request {} {}", method, path),
+                };
+                response.to_string().into_bytes()
+            })
+        };
+        (url, saved)
+    }
+
+    #[test]
+    fn apply_keeps_live_chapters() {
+        let (url, saved) = live_card_server();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("card.yaml");
+        fs::write(
+            &path,
+            r#"
+id: 5WsQ9
+title: Stories
+chapters:
+  - title: The Owl
+    icon: "yoto:#owl"
+    url: "yoto:#a"
+"#,
+        )
+        .unwrap();
+
+        let client = client(&url);
+        plan(&client, &path)
+            .unwrap()
+            .apply(&client, |_| ())
+            .unwrap();
+        let chapter = &saved.lock().unwrap()["content"]["chapters"][0];
+        assert_eq!(chapter["title"], "The Owl");
+        assert_eq!(chapter["availableFrom"], "2025-12-01");
+        assert_eq!(chapter["display"], json!({ "icon16x16": "yoto:#owl" }));
+        assert_eq!(chapter["duration"], 20);
+        assert_eq!(chapter["tracks"][0]["title"], "The Owl");
+        assert_eq!(
+            chapter["tracks"][0]["display"],
+            json!({ "icon16x16": "yoto:#owl", "iconUrl16x16": "https://owl" })
+        );
+    }
+
+    #[test]
+    fn apply_needs_known_audio() {
+        let (url, saved) = live_card_server();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("card.yaml");
+        fs::write(
+            &path,
+            r#"
+id: 5WsQ9
+title: Stories
+chapters:
+  - title: The Owl
+    url: "yoto:#a"
+  - title: The Bear
+    url: "yoto:#b"
+"#,
+        )
+        .unwrap();
+        let client = client(&url);
+        let result = plan(&client, &path).unwrap().apply(&client, |_| ());
+        assert!(matches!(result, Err(Error::Manifest(_))));
+        assert_eq!(*saved.lock().unwrap(), Value::Null);
+
+        /* Audio uploaded from another file of the manifest is known */
+        let locked = LockedFile {
+            sha256: "b".to_string(),
+            info: TranscodedInfo {
+                duration: Some(30.0),
+                format: Some("mp3".to_string()),
+                ..TranscodedInfo::default()
+            },
+            ..LockedFile::default()
+        };
+        let lock = Lock {
+            card_id: None,
+            files: BTreeMap::from([("bear.mp3".to_string(), locked)]),
+        };
+        lock.save(&Lock::path(&path)).unwrap();
+        plan(&client, &path)
+            .unwrap()
+            .apply(&client, |_| ())
+            .unwrap();
+        let track = &saved.lock().unwrap()["content"]["chapters"][1]["tracks"][0];
+        assert_eq!(track["trackUrl"], "yoto:#b");
+        assert_eq!(track["duration"], 30);
+        assert_eq!(track["format"], "mp3");
+    }
+}
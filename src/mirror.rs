@@ -0,0 +1,360 @@
+//! Incremental mirror of all the cards of an account.
+//!
+//! Every card is backed up to its own directory, laid out as described in
+//! [`crate::backup`]. An index file records what was downloaded, so that
+//! later runs only fetch the cards whose content changed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::api::Client;
+use crate::backup::{self, CARD_FILE};
+use crate::error::{Error, Result};
+use crate::model::Card;
+
+pub const INDEX_FILE: &str = "index.json";
+/// Directory, within the mirror, where deleted cards are archived.
+pub const ARCHIVE_DIR: &str = "deleted";
+
+/// What to do with the cards that were deleted from the account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deleted {
+    /// Move them to the [`ARCHIVE_DIR`] directory.
+    Archive,
+    /// Remove them from the mirror.
+    Prune,
+}
+
+/// Mirrored card, as recorded in the index file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Entry {
+    pub title: String,
+    /// Directory of the card, relative to the mirror.
+    pub dir: String,
+    pub updated_at: String,
+    /// References of the audio and icons of the card.
+    pub media: Vec<String>,
+    /// Paths of the media files, as laid out by [`backup::media_files`].
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Index {
+    pub cards: BTreeMap<String, Entry>,
+}
+
+/// Outcome of a mirror run, as lists of card IDs.
+#[derive(Debug, Default)]
+pub struct Mirror {
+    pub downloaded: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Cards that could not be mirrored, with the reason. They are tried
+    /// again on the next run.
+    pub failed: Vec<(String, Error)>,
+}
+
+impl Index {
+    pub fn load(dir: &Path) -> Result<Index> {
+        match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves the index atomically, so that an interrupted run does not lose
+    /// track of the cards already mirrored.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.persist(dir.join(INDEX_FILE)).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Lists the references of the audio and icons of a card, which change
+/// whenever its media do.
+pub fn media_refs(card: &Card) -> Vec<String> {
+    let mut refs = Vec::new();
+    for chapter in card.content.chapters.iter() {
+        refs.extend(chapter.display.iter().filter_map(|icon| icon.small.clone()));
+        for track in chapter.tracks.iter() {
+            refs.push(track.track_url.clone());
+            refs.extend(track.icon.iter().filter_map(|icon| icon.small.clone()));
+        }
+    }
+    refs
+}
+
+fn remove_dir(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Mirrors a card whose update timestamp changed, returning its new index
+/// entry and whether its media were downloaded.
+fn mirror_card(
+    client: &Client,
+    dest: &Path,
+    summary: &Card,
+    old: Option<&Entry>,
+) -> Result<(Entry, bool)> {
+    let card = client.get_card(&summary.card_id, false)?;
+    let entry = Entry {
+        title: card.title.clone(),
+        dir: backup::root_name(&card),
+        updated_at: if summary.updated_at.is_empty() {
+            card.updated_at.clone()
+        } else {
+            summary.updated_at.clone()
+        },
+        media: media_refs(&card),
+        files: backup::media_files(&card)
+            .into_iter()
+            .map(|file| file.path)
+            .collect(),
+    };
+
+    match old {
+        /* Only the card metadata changed: no need to download the media
+         * again. Chapter and track titles are part of the file names, so
+         * the media must also be laid out identically. */
+        Some(old)
+            if old.media == entry.media
+                && old.files == entry.files
+                && dest.join(&old.dir).join(CARD_FILE).is_file() =>
+        {
+            if old.dir != entry.dir {
+                remove_dir(&dest.join(&entry.dir))?;
+                fs::rename(dest.join(&old.dir), dest.join(&entry.dir))?;
+            }
+            let json = serde_json::to_vec_pretty(&card)?;
+            fs::write(dest.join(&entry.dir).join(CARD_FILE), json)?;
+            Ok((entry, false))
+        }
+        old => {
+            if let Some(old) = old {
+                remove_dir(&dest.join(&old.dir))?;
+            }
+            remove_dir(&dest.join(&entry.dir))?;
+            backup::backup(client, &card, dest, false)?;
+            Ok((entry, true))
+        }
+    }
+}
+
+/// Mirrors all the cards of the account to `dest`.
+///
+/// Cards whose update timestamp did not change since the last run are
+/// skipped. Otherwise, their media are only downloaded again if their
+/// references or file names changed. A card that fails to be mirrored is
+/// reported in [`Mirror::failed`] without stopping the others.
+pub fn mirror(client: &Client, dest: &Path, deleted: Deleted) -> Result<Mirror> {
+    fs::create_dir_all(dest)?;
+    let mut index = Index::load(dest)?;
+    let mut result = Mirror::default();
+
+    let cards = client.get_cards()?;
+    for summary in cards.iter() {
+        let id = &summary.card_id;
+        let old = index.cards.get(id);
+        if let Some(entry) = old {
+            if !summary.updated_at.is_empty()
+                && summary.updated_at == entry.updated_at
+                && dest.join(&entry.dir).join(CARD_FILE).is_file()
+            {
+                result.unchanged.push(id.clone());
+                continue;
+            }
+        }
+
+        match mirror_card(client, dest, summary, old) {
+            Ok((entry, downloaded)) => {
+                if downloaded {
+                    result.downloaded.push(id.clone());
+                } else {
+                    result.unchanged.push(id.clone());
+                }
+                index.cards.insert(id.clone(), entry);
+                index.save(dest)?;
+            }
+            Err(err) => result.failed.push((id.clone(), err)),
+        }
+    }
+
+    let listed: HashSet<&str> = cards.iter().map(|card| card.card_id.as_str()).collect();
+    let gone: Vec<String> = index
+        .cards
+        .keys()
+        .filter(|id| !listed.contains(id.as_str()))
+        .cloned()
+        .collect();
+    for id in gone {
+        let entry = index.cards.remove(&id).unwrap_or_default();
+        let path = dest.join(&entry.dir);
+        match deleted {
+            Deleted::Archive if path.is_dir() => {
+                let archive = dest.join(ARCHIVE_DIR);
+                fs::create_dir_all(&archive)?;
+                remove_dir(&archive.join(&entry.dir))?;
+                fs::rename(&path, archive.join(&entry.dir))?;
+            }
+            Deleted::Archive => (),
+            Deleted::Prune => remove_dir(&path)?,
+        }
+        result.deleted.push(id);
+    }
+    index.save(dest)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::media_url;
+    use crate::testing::{client, serve, serve_with_status};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn media_refs_follow_card_order() {
+        let card: Card = serde_json::from_value(json!({
+            "cardId": "5WsQ9",
+            "content": {
+                "chapters": [{
+                    "key": "01",
+                    "title": "Fox",
+                    "display": { "icon16x16": "yoto:#icon1" },
+                    "tracks": [{
+                        "key": "01", "title": "One", "trackUrl": "yoto:#sha1",
+                        "type": "audio", "format": "mp3", "overlayLabel": "1",
+                        "duration": 10, "fileSize": 100,
+                        "display": { "icon16x16": "yoto:#icon2" }
+                    }, {
+                        "key": "02", "title": "Two", "trackUrl": "yoto:#sha2",
+                        "type": "audio", "format": "mp3", "overlayLabel": "2",
+                        "duration": 10, "fileSize": 100
+                    }]
+                }]
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            media_refs(&card),
+            vec!["yoto:#icon1", "yoto:#sha1", "yoto:#icon2", "yoto:#sha2"]
+        );
+    }
+
+    #[test]
+    fn continue_after_failed_card() {
+        let url = serve_with_status(|_, path, _| {
+            let card = |id: &str| json!({ "cardId": id, "title": id, "updatedAt": "1" });
+            match path.split('?').next().unwrap() {
+                "/content/mine" => {
+                    let cards = json!({ "cards": [card("broken"), card("5WsQ9")] });
+                    (200, cards.to_string().into_bytes())
+                }
+                "/content/broken" => (500, b"{}".to_vec()),
+                "/content/5WsQ9" => {
+                    let card = json!({ "card": card("5WsQ9") });
+                    (200, card.to_string().into_bytes())
+                }
+                _ => panic!("unexpected request {}", path),
+            }
+        });
+        let client = client(&url);
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = mirror(&client, dir.path(), Deleted::Archive).unwrap();
+        assert_eq!(result.downloaded, vec!["5WsQ9"]);
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].0, "broken");
+        assert!(matches!(result.failed[0].1, Error::Http { .. }));
+        let index = Index::load(dir.path()).unwrap();
+        assert_eq!(index.cards.keys().collect::<Vec<_>>(), vec!["5WsQ9"]);
+    }
+
+    #[test]
+    fn restore_after_renaming_a_track() {
+        let title = Arc::new(Mutex::new("One".to_string()));
+        let url = Arc::new(Mutex::new(String::new()));
+        let card = {
+            let (title, url) = (title.clone(), url.clone());
+            move |playable: bool| {
+                let title = title.lock().unwrap().clone();
+                let media = |id: &str, kind: &str| {
+                    if playable {
+                        format!("{}/{}/{}", url.lock().unwrap(), kind, id)
+                    } else {
+                        media_url(id)
+                    }
+                };
+                json!({
+                    "cardId": "5WsQ9",
+                    "title": "Stories",
+                    "updatedAt": format!("updated {}", title),
+                    "content": {
+                        "chapters": [{
+                            "key": "01",
+                            "title": "Fox",
+                            "tracks": [{
+                                "key": "01", "title": title,
+                                "trackUrl": media("sha1", "audio"),
+                                "type": "audio", "format": "mp3", "overlayLabel": "1",
+                                "duration": 10, "fileSize": 100,
+                                "display": { "icon16x16": media("icon1", "icons") }
+                            }]
+                        }]
+                    }
+                })
+            }
+        };
+        *url.lock().unwrap() = serve(move |method, path, body| {
+            let response = match (method, path.split('?').next().unwrap()) {
+                ("GET", "/content/mine") => json!({ "cards": [card(false)] }),
+                ("GET", "/content/5WsQ9") => json!({ "card": card(path.contains("playable")) }),
+                ("GET", "/media/displayIcons/user/me" | "/media/displayIcons/user/yoto") => {
+                    json!({ "displayIcons": [] })
+                }
+                ("POST", "/media/displayIcons/user/me/upload") => {
+                    json!({ "displayIcon": { "mediaId": "icon2" } })
+                }
+                ("POST", "/content") => {
+                    json!({ "card": serde_json::from_slice::<Value>(body).unwrap() })
+                }
+                ("GET", _) => return path.as_bytes().to_vec(),
+                _ => panic!("unexpected request {} {}", method, path),
+            };
+            response.to_string().into_bytes()
+        });
+        let client = client(&url.lock().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = mirror(&client, dir.path(), Deleted::Archive).unwrap();
+        assert_eq!(result.downloaded, vec!["5WsQ9"]);
+        *title.lock().unwrap() = "Uno".to_string();
+        let result = mirror(&client, dir.path(), Deleted::Archive).unwrap();
+        assert_eq!(result.downloaded, vec!["5WsQ9"]);
+
+        let root = dir.path().join("Stories (5WsQ9)");
+        assert!(root.join("01 - Fox/01 - Uno.mp3").is_file());
+        assert!(!root.join("01 - Fox/01 - One.mp3").exists());
+        let restore = backup::restore_card(&client, &root, None, false).unwrap();
+        assert_eq!(restore.uploaded, vec!["01 - Fox/01 - Uno.icon"]);
+        let track = &restore.card.content.chapters[0].tracks[0];
+        assert_eq!(track.title, "Uno");
+        assert_eq!(
+            track.icon.as_ref().unwrap().small.as_deref(),
+            Some("yoto:#icon2")
+        );
+    }
+}
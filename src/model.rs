@@ -1,11 +1,20 @@
+//! Types returned by the Yoto REST API.
+//!
+//! They only model part of the server schema: the other fields are kept in
+//! their `extra` map and serialized back, so that updating a card or a
+//! setting does not erase what this library does not know about.
 #![allow(dead_code)]
 
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
 use std::default::Default;
 
+use crate::error::Error;
+
 #[derive(Deserialize, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
 pub struct Device {
     #[serde(rename = "deviceId")]
     pub id: String,
@@ -16,33 +25,117 @@ pub struct Device {
     device_type: Option<String>,
     family: Option<String>,
     group: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-/*#[derive(Deserialize, Serialize)]
-pub enum CardType {
-    None = 0,
-    Physical = 1,
-    Remote = 2,
+/// Declares an enum for a numeric code of the API. Codes this library does
+/// not know are kept as `Unknown`, and serialized back as they were.
+macro_rules! code_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident = $code:literal,)+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+        #[serde(from = "i64", into = "i64")]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)+
+            Unknown(i64),
+        }
+
+        impl From<i64> for $name {
+            fn from(code: i64) -> $name {
+                match code {
+                    $($code => $name::$variant,)+
+                    code => $name::Unknown(code),
+                }
+            }
+        }
+
+        impl From<$name> for i64 {
+            fn from(value: $name) -> i64 {
+                match value {
+                    $($name::$variant => $code,)+
+                    $name::Unknown(code) => code,
+                }
+            }
+        }
+    };
 }
-pub enum DayMode {
-    Unknown = -1,
-    Night = 0,
-    Day = 1,
+#[cfg(feature = "mqtt")]
+pub(crate) use code_enum;
+
+code_enum! {
+    /// How the current card was inserted.
+    pub enum CardType {
+        None = 0,
+        Physical = 1,
+        Remote = 2,
+    }
 }
+
+code_enum! {
+    /// Whether the player uses its day or night settings. The player reports
+    /// -1 when it does not know yet.
+    pub enum DayMode {
+        Night = 0,
+        Day = 1,
+    }
+}
+
+code_enum! {
+    pub enum PowerSource {
+        Battery = 0,
+        V2Dock = 1,
+        UsbC = 2,
+        QiDock = 3,
+    }
+}
+
+impl std::fmt::Display for PowerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PowerSource::Battery => write!(f, "battery"),
+            PowerSource::V2Dock => write!(f, "dock"),
+            PowerSource::UsbC => write!(f, "USB-C"),
+            PowerSource::QiDock => write!(f, "Qi dock"),
+            PowerSource::Unknown(code) => write!(f, "power source {}", code),
+        }
+    }
+}
+
+/// Nightlight setting: off, or the colour of the light (e.g. `"0x194a55"`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
 pub enum NightlightMode {
     Off,
     On(String),
 }
-pub enum PowerSource {
-    Battery = 0,
-    V2Dock = 1,
-    USB-C = 2,
-    QiDock = 3,
+
+impl From<String> for NightlightMode {
+    fn from(mode: String) -> NightlightMode {
+        if mode == "off" {
+            NightlightMode::Off
+        } else {
+            NightlightMode::On(mode)
+        }
+    }
 }
-*/
 
-#[derive(Deserialize)]
-#[serde(rename_all = "snake_case")]
+impl From<NightlightMode> for String {
+    fn from(mode: NightlightMode) -> String {
+        match mode {
+            NightlightMode::Off => String::from("off"),
+            NightlightMode::On(colour) => colour,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DeviceStatus {
     #[serde(rename = "deviceId")]
     id: String,
@@ -53,9 +146,9 @@ pub struct DeviceStatus {
 
     /* Mode */
     active_card: String,
-    card_insertion_state: u32,
-    day_mode: u32,
-    nightlight_mode: String,
+    card_insertion_state: CardType,
+    day_mode: DayMode,
+    nightlight_mode: NightlightMode,
 
     /* Network */
     #[serde(rename = "isBackgroundDownloadActive")]
@@ -65,14 +158,14 @@ pub struct DeviceStatus {
     #[serde(rename = "isOnline")]
     online: bool,
     network_ssid: String,
-    wifi_strength: u32,
+    wifi_strength: i32,
 
     /* Power */
     #[serde(rename = "isCharging")]
     charging: bool,
     #[serde(rename = "batteryLevelPercentage")]
     battery_level: u32,
-    power_source: u32,
+    power_source: PowerSource,
 
     /* Audio */
     #[serde(rename = "userVolumePercentage")]
@@ -93,10 +186,204 @@ pub struct DeviceStatus {
     ambient_light: Option<String>,
     #[serde(rename = "temperatureCelsius")]
     temperature: u32,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl DeviceStatus {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    pub fn online(&self) -> bool {
+        self.online
+    }
+
+    /// ID of the card being played, `"none"` if there is none.
+    pub fn active_card(&self) -> &str {
+        &self.active_card
+    }
+
+    pub fn network_ssid(&self) -> &str {
+        &self.network_ssid
+    }
+
+    /// Wi-Fi signal strength, in dBm.
+    pub fn wifi_strength(&self) -> i32 {
+        self.wifi_strength
+    }
+
+    pub fn charging(&self) -> bool {
+        self.charging
+    }
+
+    /// Battery level, in percent.
+    pub fn battery_level(&self) -> u32 {
+        self.battery_level
+    }
+
+    /// Volume set by the user, in percent.
+    pub fn user_volume(&self) -> u32 {
+        self.user_volume
+    }
+
+    /// Volume of the player, in percent of its maximum volume.
+    pub fn system_volume(&self) -> u32 {
+        self.system_volume
+    }
+
+    pub fn free_disk_space(&self) -> u64 {
+        self.free_disk_space
+    }
+
+    pub fn total_disk_space(&self) -> u64 {
+        self.total_disk_space
+    }
+
+    /// Temperature, in degrees Celsius.
+    pub fn temperature(&self) -> u32 {
+        self.temperature
+    }
+
+    pub fn card_type(&self) -> CardType {
+        self.card_insertion_state
+    }
+
+    pub fn day_mode(&self) -> DayMode {
+        self.day_mode
+    }
+
+    pub fn nightlight_mode(&self) -> &NightlightMode {
+        &self.nightlight_mode
+    }
+
+    pub fn power_source(&self) -> PowerSource {
+        self.power_source
+    }
+}
+
+/// Display brightness: automatic, from the light sensor, or in percent.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum Brightness {
+    Auto,
+    Level(u32),
+    Unknown(String),
+}
+
+impl From<String> for Brightness {
+    fn from(value: String) -> Brightness {
+        if value == "auto" {
+            Brightness::Auto
+        } else if let Ok(level) = value.parse() {
+            Brightness::Level(level)
+        } else {
+            Brightness::Unknown(value)
+        }
+    }
+}
+
+impl From<Brightness> for String {
+    fn from(value: Brightness) -> String {
+        match value {
+            Brightness::Auto => String::from("auto"),
+            Brightness::Level(level) => level.to_string(),
+            Brightness::Unknown(value) => value,
+        }
+    }
+}
+
+/// Times of day, encoded as `"07:30"`.
+mod time_of_day {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &Option<NaiveTime>, s: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => s.serialize_str(&time.format("%H:%M").to_string()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<NaiveTime>, D::Error> {
+        match Option::<String>::deserialize(d)? {
+            Some(time) => NaiveTime::parse_from_str(&time, "%H:%M")
+                .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M:%S"))
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Flags, encoded as `"0"` or `"1"`.
+mod flag {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(flag: &Option<bool>, s: S) -> Result<S::Ok, S::Error> {
+        match flag {
+            Some(flag) => s.serialize_str(if *flag { "1" } else { "0" }),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<bool>, D::Error> {
+        Ok(match Option::<Value>::deserialize(d)? {
+            Some(Value::Bool(flag)) => Some(flag),
+            Some(Value::String(flag)) => Some(!matches!(flag.as_str(), "" | "0" | "false")),
+            Some(Value::Number(flag)) => Some(flag.as_u64() != Some(0)),
+            _ => None,
+        })
+    }
+}
+
+/// Settings of a player.
+///
+/// The API encodes most values as strings; they are converted here and back.
+/// Fields left to `None` are not changed by
+/// [`Client::update_device_config`](crate::api::Client::update_device_config).
+#[serde_as]
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeviceConfig {
+    /// Start of the day settings.
+    #[serde(with = "time_of_day")]
+    pub day_time: Option<NaiveTime>,
+    /// Start of the night settings.
+    #[serde(with = "time_of_day")]
+    pub night_time: Option<NaiveTime>,
+    /// Maximum volume during the day, from 0 to 16.
+    #[serde_as(as = "Option<PickFirst<(DisplayFromStr, _)>>")]
+    pub max_volume_limit: Option<u32>,
+    /// Maximum volume during the night, from 0 to 16.
+    #[serde_as(as = "Option<PickFirst<(DisplayFromStr, _)>>")]
+    pub night_max_volume_limit: Option<u32>,
+    pub day_display_brightness: Option<Brightness>,
+    pub night_display_brightness: Option<Brightness>,
+    /// Clock format of the display, 12 or 24 hours.
+    #[serde_as(as = "Option<PickFirst<(DisplayFromStr, _)>>")]
+    pub hour_format: Option<u32>,
+    /// Nightlight colour during the day, e.g. `"#40bfd9"`.
+    pub ambient_colour: Option<String>,
+    /// Nightlight colour during the night.
+    pub night_ambient_colour: Option<String>,
+    /// Time after which the player turns itself off when idle, in seconds.
+    #[serde_as(as = "Option<PickFirst<(DisplayFromStr, _)>>")]
+    pub shutdown_timeout: Option<u32>,
+    #[serde(with = "flag")]
+    pub bluetooth_enabled: Option<bool>,
+    /// Alarms, as encoded by the API; see [`crate::alarm`].
+    pub alarms: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
 pub struct Image {
     #[serde(rename = "imageId")]
     id: String,
@@ -104,18 +391,22 @@ pub struct Image {
     etag: String,
     last_modified: DateTime<Utc>,
     size: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
 pub struct DisplayIcon {
     #[serde(rename = "displayIconId")]
     id: String,
-    media_id: String,
+    pub media_id: String,
     public: bool,
     url: String,
     created_at: DateTime<Utc>,
     user_id: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -139,13 +430,23 @@ pub enum MediaFormat {
 }
 
 impl MediaFormat {
-    pub fn from_ext(ext: &str) -> Result<MediaFormat, String> {
-        match ext {
+    pub fn from_ext(ext: &str) -> Result<MediaFormat, Error> {
+        match ext.to_ascii_lowercase().as_ref() {
             "mp3" => Ok(MediaFormat::Mp3),
             "aac" => Ok(MediaFormat::Aac),
             "ogg" => Ok(MediaFormat::Ogg),
             "opus" => Ok(MediaFormat::Opus),
-            _ => Err("Unsupported file extension".to_string()),
+            _ => Err(Error::UnsupportedFormat(ext.to_string())),
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            MediaFormat::Mp3 => "mp3",
+            MediaFormat::Aac => "aac",
+            MediaFormat::Ogg => "ogg",
+            MediaFormat::Opus => "opus",
+            MediaFormat::Unknown(f) => f,
         }
     }
 
@@ -169,7 +470,7 @@ pub enum ChannelType {
     Unknown(String),
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PlaybackType {
     #[default]
@@ -179,6 +480,7 @@ pub enum PlaybackType {
     Unknown(String),
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
@@ -186,77 +488,413 @@ pub struct Card {
     pub title: String,
     slug: Option<String>,
     sort_key: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     availability: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub card_id: String,
-    content: CardContent,
+    pub content: CardContent,
+    #[serde(skip_serializing_if = "String::is_empty")]
     created_at: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub updated_at: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     deleted: bool,
-    metadata: CardMetadata,
+    pub metadata: CardMetadata,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct CardContent {
     version: String,
-    chapters: Vec<Chapter>,
-    config: ContentConfig,
-    playback_type: PlaybackType,
+    pub chapters: Vec<Chapter>,
+    pub config: ContentConfig,
+    pub playback_type: PlaybackType,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ContentConfig {
-    autoadvance: Option<bool>,
-    resume_timeout: Option<u64>,
-    system_activity: Option<bool>,
-    track_number_overlay_timeout: Option<u64>,
+    pub autoadvance: Option<bool>,
+    pub resume_timeout: Option<u64>,
+    pub system_activity: Option<bool>,
+    pub track_number_overlay_timeout: Option<u64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct CardMetadata {
-    author: String,
-    category: String,
-    description: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub category: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Chapter {
-    key: String,
-    title: String,
-    overlay_label: Option<String>,
+    pub key: String,
+    pub title: String,
+    pub overlay_label: Option<String>,
     overlay_label_override: Option<String>,
-    tracks: Vec<Track>,
+    pub tracks: Vec<Track>,
     default_track_display: Option<String>,
     default_track_ambient: Option<String>,
-    duration: Option<u64>,
-    file_size: Option<u64>,
-    display: Option<Icon>,
+    pub duration: Option<u64>,
+    pub file_size: Option<u64>,
+    pub display: Option<Icon>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Track {
-    title: String,
-    track_url: String,
-    key: String,
+    pub title: String,
+    pub track_url: String,
+    pub key: String,
     uid: Option<String>,
     #[serde(rename = "type")]
-    media: MediaType,
-    format: MediaFormat,
+    pub media: MediaType,
+    pub format: MediaFormat,
     #[serde(rename = "display")]
-    icon: Option<Icon>,
+    pub icon: Option<Icon>,
     overlay_label_override: Option<String>,
-    overlay_label: String,
-    duration: u64,
-    file_size: u64,
+    pub overlay_label: String,
+    pub duration: u64,
+    pub file_size: u64,
     channels: Option<ChannelType>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Icon {
     #[serde(rename = "icon16x16")]
-    small: Option<String>,
+    pub small: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Reference to uploaded media, such as transcoded audio or a display icon,
+/// as used in card content.
+pub fn media_url(id: &str) -> String {
+    format!("yoto:#{}", id)
+}
+
+impl Card {
+    /// Starts a new card, to be submitted with
+    /// [`Client::create_card`](crate::api::Client::create_card).
+    pub fn new(title: &str) -> Card {
+        Card {
+            title: title.to_string(),
+            content: CardContent {
+                version: "1".to_string(),
+                ..CardContent::default()
+            },
+            ..Card::default()
+        }
+    }
+
+    /// Forgets the creation and update times set by the server, which must
+    /// not be sent back when saving the card as new content.
+    pub(crate) fn clear_timestamps(&mut self) {
+        self.created_at.clear();
+        self.updated_at.clear();
+    }
+
+    pub fn author(mut self, author: &str) -> Card {
+        self.metadata.author = author.to_string();
+        self
+    }
+
+    pub fn category(mut self, category: &str) -> Card {
+        self.metadata.category = category.to_string();
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Card {
+        self.metadata.description = description.to_string();
+        self
+    }
+
+    pub fn chapter(mut self, chapter: Chapter) -> Card {
+        self.content.chapters.push(chapter);
+        self
+    }
+
+    pub fn config(mut self, config: ContentConfig) -> Card {
+        self.content.config = config;
+        self
+    }
+
+    pub fn playback_type(mut self, playback_type: PlaybackType) -> Card {
+        self.content.playback_type = playback_type;
+        self
+    }
+}
+
+impl ContentConfig {
+    pub fn autoadvance(mut self, autoadvance: bool) -> ContentConfig {
+        self.autoadvance = Some(autoadvance);
+        self
+    }
+
+    pub fn resume_timeout(mut self, seconds: u64) -> ContentConfig {
+        self.resume_timeout = Some(seconds);
+        self
+    }
+
+    pub fn system_activity(mut self, system_activity: bool) -> ContentConfig {
+        self.system_activity = Some(system_activity);
+        self
+    }
+
+    pub fn track_number_overlay_timeout(mut self, seconds: u64) -> ContentConfig {
+        self.track_number_overlay_timeout = Some(seconds);
+        self
+    }
+}
+
+impl Chapter {
+    pub fn new(key: &str, title: &str) -> Chapter {
+        Chapter {
+            key: key.to_string(),
+            title: title.to_string(),
+            overlay_label: None,
+            overlay_label_override: None,
+            tracks: Vec::new(),
+            default_track_display: None,
+            default_track_ambient: None,
+            duration: None,
+            file_size: None,
+            display: None,
+            extra: Map::new(),
+        }
+    }
+
+    pub fn overlay_label(mut self, label: &str) -> Chapter {
+        self.overlay_label = Some(label.to_string());
+        self
+    }
+
+    /// Sets the icon, given as a [`media_url`] reference.
+    pub fn icon(mut self, icon: &str) -> Chapter {
+        self.display = Some(Icon::new(icon));
+        self
+    }
+
+    /// Appends a track, adding its duration and size to the chapter ones.
+    pub fn track(mut self, track: Track) -> Chapter {
+        self.duration = Some(self.duration.unwrap_or(0) + track.duration);
+        self.file_size = Some(self.file_size.unwrap_or(0) + track.file_size);
+        self.tracks.push(track);
+        self
+    }
+}
+
+impl Track {
+    /// Creates an audio track playing `url`, usually the [`media_url`] of
+    /// transcoded audio. The overlay label defaults to the key.
+    pub fn new(key: &str, title: &str, url: &str, format: MediaFormat) -> Track {
+        Track {
+            title: title.to_string(),
+            track_url: url.to_string(),
+            key: key.to_string(),
+            uid: None,
+            media: MediaType::Audio,
+            format,
+            icon: None,
+            overlay_label_override: None,
+            overlay_label: key.to_string(),
+            duration: 0,
+            file_size: 0,
+            channels: None,
+            extra: Map::new(),
+        }
+    }
+
+    pub fn media(mut self, media: MediaType) -> Track {
+        self.media = media;
+        self
+    }
+
+    pub fn overlay_label(mut self, label: &str) -> Track {
+        self.overlay_label = label.to_string();
+        self
+    }
+
+    /// Sets the icon, given as a [`media_url`] reference.
+    pub fn icon(mut self, icon: &str) -> Track {
+        self.icon = Some(Icon::new(icon));
+        self
+    }
+
+    pub fn duration(mut self, seconds: u64) -> Track {
+        self.duration = seconds;
+        self
+    }
+
+    pub fn file_size(mut self, bytes: u64) -> Track {
+        self.file_size = bytes;
+        self
+    }
+
+    pub fn channels(mut self, channels: ChannelType) -> Track {
+        self.channels = Some(channels);
+        self
+    }
+}
+
+impl Icon {
+    pub fn new(icon: &str) -> Icon {
+        Icon {
+            small: Some(icon.to_string()),
+            extra: Map::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn build_card() {
+        let card = Card::new("Stories")
+            .author("Me")
+            .config(ContentConfig::default().autoadvance(true))
+            .chapter(
+                Chapter::new("01", "Fox").icon(&media_url("icon")).track(
+                    Track::new("01", "Part 1", &media_url("sha"), MediaFormat::Aac)
+                        .duration(30)
+                        .file_size(1000),
+                ),
+            );
+        assert_eq!(
+            serde_json::to_value(&card).unwrap(),
+            json!({
+                "title": "Stories",
+                "content": {
+                    "version": "1",
+                    "chapters": [{
+                        "key": "01",
+                        "title": "Fox",
+                        "tracks": [{
+                            "title": "Part 1",
+                            "trackUrl": "yoto:#sha",
+                            "key": "01",
+                            "type": "audio",
+                            "format": "aac",
+                            "overlayLabel": "01",
+                            "duration": 30,
+                            "fileSize": 1000
+                        }],
+                        "duration": 30,
+                        "fileSize": 1000,
+                        "display": { "icon16x16": "yoto:#icon" }
+                    }],
+                    "config": { "autoadvance": true },
+                    "playbackType": "linear"
+                },
+                "metadata": { "author": "Me" }
+            })
+        );
+    }
+
+    fn round_trip<T>(sample: &str)
+    where
+        T: serde::de::DeserializeOwned + Serialize,
+    {
+        let value: Value = serde_json::from_str(sample).unwrap();
+        let model: T = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&model).unwrap(), value);
+    }
+
+    #[test]
+    fn card_round_trip() {
+        let mut sample: Value =
+            serde_json::from_str(include_str!("../tests/data/card.json")).unwrap();
+        let mut value = sample["card"].take();
+        let card: Card = serde_json::from_value(value.clone()).unwrap();
+        /* Cards are not deleted by default, which is left out */
+        assert_eq!(value["deleted"], json!(false));
+        value.as_object_mut().unwrap().remove("deleted");
+        assert_eq!(serde_json::to_value(&card).unwrap(), value);
+        value["deleted"] = json!(true);
+        round_trip::<Card>(&value.to_string());
+
+        assert_eq!(card.content.extra["restricted"], json!(true));
+        assert_eq!(card.metadata.extra["tags"], json!(["bedtime", "animals"]));
+        assert_eq!(card.content.chapters[0].extra["availableFrom"], Value::Null);
+    }
+
+    #[test]
+    fn device_status_round_trip() {
+        round_trip::<DeviceStatus>(include_str!("../tests/data/device_status.json"));
+    }
+
+    #[test]
+    fn device_status_codes() {
+        let mut sample: Value =
+            serde_json::from_str(include_str!("../tests/data/device_status.json")).unwrap();
+        let status: DeviceStatus = serde_json::from_value(sample.clone()).unwrap();
+        assert_eq!(status.card_type(), CardType::None);
+        assert_eq!(status.day_mode(), DayMode::Day);
+        assert_eq!(status.power_source(), PowerSource::UsbC);
+        assert_eq!(
+            status.nightlight_mode(),
+            &NightlightMode::On("0x194a55".to_string())
+        );
+
+        sample["dayMode"] = json!(-1);
+        sample["powerSource"] = json!(7);
+        sample["nightlightMode"] = json!("off");
+        let status: DeviceStatus = serde_json::from_value(sample.clone()).unwrap();
+        assert_eq!(status.day_mode(), DayMode::Unknown(-1));
+        assert_eq!(status.power_source(), PowerSource::Unknown(7));
+        assert_eq!(status.nightlight_mode(), &NightlightMode::Off);
+        assert_eq!(serde_json::to_value(&status).unwrap(), sample);
+    }
+
+    #[test]
+    fn device_config_round_trip() {
+        let sample = json!({
+            "dayTime": "07:00",
+            "nightTime": "19:30",
+            "maxVolumeLimit": "16",
+            "nightMaxVolumeLimit": "8",
+            "dayDisplayBrightness": "auto",
+            "nightDisplayBrightness": "20",
+            "hourFormat": "24",
+            "ambientColour": "#40bfd9",
+            "nightAmbientColour": "#f57399",
+            "shutdownTimeout": "3600",
+            "bluetoothEnabled": "1",
+            "clockFace": "digital-sun",
+            "alarms": ["0000000,0700,4OD25,,,1"]
+        });
+        let config: DeviceConfig = serde_json::from_value(sample.clone()).unwrap();
+        assert_eq!(config.night_time, NaiveTime::from_hms_opt(19, 30, 0));
+        assert_eq!(config.night_max_volume_limit, Some(8));
+        assert_eq!(config.night_display_brightness, Some(Brightness::Level(20)));
+        assert_eq!(config.bluetooth_enabled, Some(true));
+        assert_eq!(serde_json::to_value(&config).unwrap(), sample);
+    }
 }
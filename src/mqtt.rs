@@ -1,67 +1,255 @@
+//! Messages exchanged with players over MQTT.
+
 use chrono::{DateTime, Utc};
+use rumqttc::{ConnectionError, Event as MqttEvent, MqttOptions, Outgoing, Packet, QoS, Transport};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
 use std::default::Default;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Default, Deserialize)]
-#[serde(rename_all = "camelCase")]
+use crate::api::Token;
+use crate::error::{Error, Result};
+use crate::model::{code_enum, DayMode};
+
+pub static BROKER_HOST: &str = "aqrphjqbp3u2z-ats.iot.eu-west-2.amazonaws.com";
+pub static BROKER_USERNAME: &str = "_?x-amz-customauthorizer-name=PublicJWTAuthorizer";
+
+code_enum! {
+    /// Playback state reported in [`Status`].
+    pub enum PlayingStatus {
+        Stopped = 0,
+        Playing = 1,
+        Paused = 2,
+    }
+}
+
+/// Player status, as published on the status topic.
+///
+/// Players only send the fields that changed, so every field is optional;
+/// use [`Status::merge`] to maintain a complete view.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, rename_all = "camelCase")]
 pub struct Status {
-    status_version: u32,
-    fw_version: String,
-    product_type: String,
-    battery_level: u32,
-    als: u32,
-    free_disk: u32,
-    shutdownt_imeout: u32,
-    dbat_timeout: u32, 
-    charging: bool,
-    active_card: String,
-    card_inserted: bool,
-    playing_status: u32,
-    headphones: bool,
-    dnow_brightness: u32,
-    day_bright: u32,
-    night_bright: u32,
-    bluetooth_hp: bool
-    volume: u32,
-    user_volume: u32,
-    time_format: String,
-    nightlight_mode: String,
-    temp: String,
-    day: u32,
-}
-
-#[derive(Default, Deserialize)]
-#[serde(rename_all = "camelCase")]
+    pub status_version: Option<u32>,
+    pub fw_version: Option<String>,
+    pub product_type: Option<String>,
+    pub battery_level: Option<u32>,
+    pub als: Option<u32>,
+    pub free_disk: Option<u64>,
+    pub shutdown_timeout: Option<u32>,
+    pub dbat_timeout: Option<u32>,
+    pub charging: Option<bool>,
+    pub active_card: Option<String>,
+    pub card_inserted: Option<bool>,
+    pub playing_status: Option<PlayingStatus>,
+    pub headphones: Option<bool>,
+    pub dnow_brightness: Option<u32>,
+    pub day_bright: Option<u32>,
+    pub night_bright: Option<u32>,
+    pub bluetooth_hp: Option<bool>,
+    pub volume: Option<u32>,
+    pub user_volume: Option<u32>,
+    pub time_format: Option<String>,
+    pub nightlight_mode: Option<String>,
+    pub temp: Option<String>,
+    pub day: Option<DayMode>,
+}
+
+/// Playback event, as published on the events topic.
+///
+/// Like [`Status`], events are sparse and can be merged.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, rename_all = "camelCase")]
 pub struct Event {
-    repeat_all: bool,
-    streaming: bool,
-    volume: u32,
-    volume_max: u32,
-    playback_wait: bool,
-    sleep_timer_active: bool,
-    event_utc: i64, // UNIX Timestamp
-    track_length: u32, // seconds
-    position: u32, // seconds
-    card_id: String,
-    source: String, // e.g. "card", "remote", "MQTT"
-    card_updated_at: DateTime<Utc>,
-    chapter_title: String,
-    chapter_key: String,
-    track_title: String,
-    track_key: String,
-    playback_status: String, // e.g. "playing", "paused", "stopped"
-    sleep_timer_seconds: u32, // seconds
+    pub repeat_all: Option<bool>,
+    pub streaming: Option<bool>,
+    pub volume: Option<u32>,
+    pub volume_max: Option<u32>,
+    pub playback_wait: Option<bool>,
+    pub sleep_timer_active: Option<bool>,
+    pub event_utc: Option<i64>,    // UNIX Timestamp
+    pub track_length: Option<u32>, // seconds
+    pub position: Option<u32>,     // seconds
+    pub card_id: Option<String>,
+    pub source: Option<String>, // e.g. "card", "remote", "MQTT"
+    pub card_updated_at: Option<DateTime<Utc>>,
+    pub chapter_title: Option<String>,
+    pub chapter_key: Option<String>,
+    pub track_title: Option<String>,
+    pub track_key: Option<String>,
+    pub playback_status: Option<String>, // e.g. "playing", "paused", "stopped"
+    pub sleep_timer_seconds: Option<u32>, // seconds
+}
+
+/// Acknowledgement of a command, as published on the response topic.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Response {
+    /// Result of each command, keyed by command name (e.g. `"volume": "OK"`).
+    pub status: Map<String, Value>,
+}
+
+/// Typed message received from a player.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceMessage {
+    Status(Status),
+    Event(Event),
+    Response(Response),
+}
+
+/// Latest known state of a player, merged from all its messages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlayerState {
+    pub status: Status,
+    pub event: Event,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Message received from a device, along with its merged state.
+#[derive(Clone, Debug)]
+pub struct DeviceUpdate {
+    pub device_id: String,
+    pub message: DeviceMessage,
+    pub state: PlayerState,
+}
+
+/// Copies every field set in `other` over `self`.
+macro_rules! merge_fields {
+    ($self:ident, $other:ident, $($field:ident),+) => {
+        $(
+            if $other.$field.is_some() {
+                $self.$field = $other.$field.clone();
+            }
+        )+
+    };
+}
+
+impl Status {
+    pub fn merge(&mut self, other: &Status) {
+        merge_fields!(
+            self,
+            other,
+            status_version,
+            fw_version,
+            product_type,
+            battery_level,
+            als,
+            free_disk,
+            shutdown_timeout,
+            dbat_timeout,
+            charging,
+            active_card,
+            card_inserted,
+            playing_status,
+            headphones,
+            dnow_brightness,
+            day_bright,
+            night_bright,
+            bluetooth_hp,
+            volume,
+            user_volume,
+            time_format,
+            nightlight_mode,
+            temp,
+            day
+        );
+    }
+}
+
+impl Event {
+    pub fn merge(&mut self, other: &Event) {
+        merge_fields!(
+            self,
+            other,
+            repeat_all,
+            streaming,
+            volume,
+            volume_max,
+            playback_wait,
+            sleep_timer_active,
+            event_utc,
+            track_length,
+            position,
+            card_id,
+            source,
+            card_updated_at,
+            chapter_title,
+            chapter_key,
+            track_title,
+            track_key,
+            playback_status,
+            sleep_timer_seconds
+        );
+    }
+}
+
+/// Status payloads are sometimes wrapped in a `status` object.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusPayload {
+    Wrapped { status: Status },
+    Flat(Status),
+}
+
+impl DeviceMessage {
+    /// Parses a raw message according to its topic, returning the device
+    /// identifier along with the message. Returns `None` for unknown topics.
+    pub fn parse(message: &Message) -> Result<Option<(String, DeviceMessage)>> {
+        let parts: Vec<&str> = message.topic.split('/').collect();
+        let (device_id, kind) = match parts.as_slice() {
+            ["device", id, "data", kind] => (id, *kind),
+            ["device", id, "response"] => (id, "response"),
+            _ => return Ok(None),
+        };
+        let parsed = match kind {
+            "status" => match serde_json::from_slice(&message.payload)? {
+                StatusPayload::Wrapped { status } | StatusPayload::Flat(status) => {
+                    DeviceMessage::Status(status)
+                }
+            },
+            "events" => DeviceMessage::Event(serde_json::from_slice(&message.payload)?),
+            "response" => DeviceMessage::Response(serde_json::from_slice(&message.payload)?),
+            _ => return Ok(None),
+        };
+        Ok(Some((device_id.to_string(), parsed)))
+    }
+}
+
+impl PlayerState {
+    pub fn apply(&mut self, message: &DeviceMessage) {
+        match message {
+            DeviceMessage::Status(status) => self.status.merge(status),
+            DeviceMessage::Event(event) => self.event.merge(event),
+            DeviceMessage::Response(_) => return,
+        }
+        self.updated_at = Some(Utc::now());
+    }
 }
 
+/// Card, and optionally position, to start playing on a player.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CardTarget {
-    uri: String,
-    chapterKey: Option<String>,
-    trackKey: Option<String>,
-    secondsIn: Option<u32>,
-    cutOff: Option<u32>,
-    anyButtonStop: Option<bool>,
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chapter_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds_in: Option<u32>,
+    /// Position, in seconds, at which playback stops.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cut_off: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub any_button_stop: Option<bool>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Reboot,
     GetStatus,
@@ -69,7 +257,11 @@ pub enum Command {
     SetVolume(u32),
     SetAmbient(u8, u8, u8),
     SetSleepTimer(u32),
-    ShowIcon(uri: String, timeout: u32, animated: bool),
+    ShowIcon {
+        uri: String,
+        timeout: u32,
+        animated: bool,
+    },
     Start(CardTarget),
     Stop,
     Pause,
@@ -80,3 +272,656 @@ pub enum Command {
     BluetoothDisconnect,
     BluetoothState,
 }
+
+impl CardTarget {
+    pub fn new(card_id: &str) -> CardTarget {
+        CardTarget {
+            uri: format!("https://yoto.io/{}", card_id),
+            ..CardTarget::default()
+        }
+    }
+
+    pub fn chapter(mut self, key: impl Into<String>) -> CardTarget {
+        self.chapter_key = Some(key.into());
+        self
+    }
+
+    pub fn track(mut self, key: impl Into<String>) -> CardTarget {
+        self.track_key = Some(key.into());
+        self
+    }
+
+    pub fn seconds_in(mut self, seconds: u32) -> CardTarget {
+        self.seconds_in = Some(seconds);
+        self
+    }
+
+    pub fn cut_off(mut self, seconds: u32) -> CardTarget {
+        self.cut_off = Some(seconds);
+        self
+    }
+
+    pub fn any_button_stop(mut self, stop: bool) -> CardTarget {
+        self.any_button_stop = Some(stop);
+        self
+    }
+}
+
+impl Command {
+    /// Suffix of the device command topic, after `device/{id}/command/`.
+    pub fn topic_suffix(&self) -> &'static str {
+        match self {
+            Command::Reboot => "reboot",
+            Command::GetStatus => "status/request",
+            Command::GetEvents => "events/request",
+            Command::SetVolume(_) => "volume/set",
+            Command::SetAmbient(..) => "ambients/set",
+            Command::SetSleepTimer(_) => "sleep-timer/set",
+            Command::ShowIcon { .. } => "display/preview",
+            Command::Start(_) => "card/start",
+            Command::Stop => "card/stop",
+            Command::Pause => "card/pause",
+            Command::Resume => "card/resume",
+            Command::BluetoothOn => "bluetooth/on",
+            Command::BluetoothOff => "bluetooth/off",
+            Command::BluetoothConnect => "bluetooth/connect",
+            Command::BluetoothDisconnect => "bluetooth/disconnect",
+            Command::BluetoothState => "bluetooth/state",
+        }
+    }
+
+    pub fn topic(&self, device_id: &str) -> String {
+        command_topic(device_id, self.topic_suffix())
+    }
+
+    /// JSON body of the command, if it takes any.
+    pub fn payload(&self) -> Option<Value> {
+        match self {
+            Command::SetVolume(volume) => Some(json!({ "volume": volume })),
+            Command::SetAmbient(r, g, b) => Some(json!({ "r": r, "g": g, "b": b })),
+            Command::SetSleepTimer(seconds) => Some(json!({ "seconds": seconds })),
+            Command::ShowIcon {
+                uri,
+                timeout,
+                animated,
+            } => Some(json!({
+                "uri": uri,
+                "timeout": timeout,
+                "animated": u8::from(*animated),
+            })),
+            Command::Start(target) => serde_json::to_value(target).ok(),
+            _ => None,
+        }
+    }
+
+    /// Parses a command received on a command topic, the inverse of
+    /// [`Command::topic_suffix`] and [`Command::to_bytes`].
+    pub fn parse(topic_suffix: &str, payload: &[u8]) -> Result<Command> {
+        let body = || -> Result<Value> {
+            if payload.is_empty() {
+                Ok(Value::Null)
+            } else {
+                Ok(serde_json::from_slice(payload)?)
+            }
+        };
+        let invalid = |name: &str, value: &dyn std::fmt::Display| {
+            Error::Mqtt(format!(
+                "invalid \"{}\" in {}: {}",
+                name, topic_suffix, value
+            ))
+        };
+        let field = |body: &Value, name: &str| -> Result<u64> {
+            let value = body
+                .get(name)
+                .ok_or_else(|| Error::Mqtt(format!("missing \"{}\" in {}", name, topic_suffix)))?;
+            value.as_u64().ok_or_else(|| invalid(name, value))
+        };
+        /* Values out of the range of the command fields are rejected */
+        let number = |body: &Value, name: &str| -> Result<u32> {
+            let value = field(body, name)?;
+            u32::try_from(value).map_err(|_| invalid(name, &value))
+        };
+        let byte = |body: &Value, name: &str| -> Result<u8> {
+            let value = field(body, name)?;
+            u8::try_from(value).map_err(|_| invalid(name, &value))
+        };
+        let command = match topic_suffix {
+            "reboot" => Command::Reboot,
+            "status/request" => Command::GetStatus,
+            "events/request" => Command::GetEvents,
+            "volume/set" => Command::SetVolume(number(&body()?, "volume")?),
+            "ambients/set" => {
+                let body = body()?;
+                Command::SetAmbient(byte(&body, "r")?, byte(&body, "g")?, byte(&body, "b")?)
+            }
+            "sleep-timer/set" => Command::SetSleepTimer(number(&body()?, "seconds")?),
+            "display/preview" => {
+                let body = body()?;
+                Command::ShowIcon {
+                    uri: body
+                        .get("uri")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                    timeout: number(&body, "timeout")?,
+                    animated: field(&body, "animated").unwrap_or(0) != 0,
+                }
+            }
+            "card/start" => Command::Start(serde_json::from_value(body()?)?),
+            "card/stop" => Command::Stop,
+            "card/pause" => Command::Pause,
+            "card/resume" => Command::Resume,
+            "bluetooth/on" => Command::BluetoothOn,
+            "bluetooth/off" => Command::BluetoothOff,
+            "bluetooth/connect" => Command::BluetoothConnect,
+            "bluetooth/disconnect" => Command::BluetoothDisconnect,
+            "bluetooth/state" => Command::BluetoothState,
+            other => return Err(Error::Mqtt(format!("unknown command \"{}\"", other))),
+        };
+        Ok(command)
+    }
+
+    /// Bytes published on the command topic.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.payload() {
+            Some(payload) => payload.to_string().into_bytes(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Transport used to reach the MQTT broker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MqttTransport {
+    Tcp,
+    Tls,
+    WebSocket,
+    SecureWebSocket,
+}
+
+/// Connection settings of an [`MqttClient`].
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub transport: MqttTransport,
+    /// User name sent along with the access token.
+    pub username: String,
+    /// Client identifier, randomly generated if not set.
+    pub client_id: Option<String>,
+    pub keep_alive: Duration,
+    pub connect_timeout: Duration,
+}
+
+/// Raw message received on one of the device topics.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Client connected to the MQTT broker used by players.
+pub struct MqttClient {
+    client: rumqttc::Client,
+    connection: rumqttc::Connection,
+    pending_acks: usize,
+    received: VecDeque<Message>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            host: BROKER_HOST.to_string(),
+            port: 443,
+            transport: MqttTransport::SecureWebSocket,
+            username: BROKER_USERNAME.to_string(),
+            client_id: None,
+            keep_alive: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Plain TCP connection to a local broker, such as mosquitto.
+    pub fn local(host: &str, port: u16) -> MqttConfig {
+        MqttConfig {
+            host: host.to_string(),
+            port,
+            transport: MqttTransport::Tcp,
+            username: "yoto-rs".to_string(),
+            ..MqttConfig::default()
+        }
+    }
+
+    fn options(&self, token: &Token) -> MqttOptions {
+        let client_id = match &self.client_id {
+            Some(id) => id.clone(),
+            None => format!("yoto-rs-{}", uuid::Uuid::new_v4().to_simple()),
+        };
+        let (host, transport) = match self.transport {
+            MqttTransport::Tcp => (self.host.clone(), Transport::tcp()),
+            MqttTransport::Tls => (self.host.clone(), Transport::tls_with_default_config()),
+            MqttTransport::WebSocket => (
+                format!("ws://{}:{}/mqtt", self.host, self.port),
+                Transport::ws(),
+            ),
+            MqttTransport::SecureWebSocket => (
+                format!("wss://{}:{}/mqtt", self.host, self.port),
+                Transport::wss_with_default_config(),
+            ),
+        };
+        let mut options = MqttOptions::new(client_id, host, self.port);
+        options
+            .set_transport(transport)
+            .set_keep_alive(self.keep_alive)
+            .set_clean_session(true)
+            .set_credentials(self.username.clone(), token.access_token());
+        options
+    }
+}
+
+pub fn status_topic(device_id: &str) -> String {
+    format!("device/{}/data/status", device_id)
+}
+
+pub fn events_topic(device_id: &str) -> String {
+    format!("device/{}/data/events", device_id)
+}
+
+pub fn response_topic(device_id: &str) -> String {
+    format!("device/{}/response", device_id)
+}
+
+pub fn command_topic(device_id: &str, command: &str) -> String {
+    format!("device/{}/command/{}", device_id, command)
+}
+
+impl Message {
+    /// Identifier of the device the message was published for.
+    pub fn device_id(&self) -> Option<&str> {
+        let mut parts = self.topic.split('/');
+        match (parts.next(), parts.next()) {
+            (Some("device"), Some(id)) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl MqttClient {
+    /// Connects to the broker, authenticating with the OAuth access token.
+    pub fn connect(config: &MqttConfig, token: &Token) -> Result<MqttClient> {
+        let (client, connection) = rumqttc::Client::new(config.options(token), 16);
+        let mut mqtt = MqttClient {
+            client,
+            connection,
+            pending_acks: 0,
+            received: VecDeque::new(),
+        };
+
+        let deadline = Instant::now() + config.connect_timeout;
+        loop {
+            match mqtt.poll(deadline)? {
+                Some(MqttEvent::Incoming(Packet::ConnAck(_))) => return Ok(mqtt),
+                Some(_) => (),
+                None => return Err(Error::Mqtt("timed out connecting to broker".to_string())),
+            }
+        }
+    }
+
+    /// Subscribes to the status, events and response topics of a device.
+    pub fn subscribe(&mut self, device_id: &str) -> Result<()> {
+        for topic in [
+            status_topic(device_id),
+            events_topic(device_id),
+            response_topic(device_id),
+        ] {
+            self.subscribe_topic(&topic)?;
+        }
+        Ok(())
+    }
+
+    /// Subscribes to an arbitrary topic filter.
+    pub fn subscribe_topic(&mut self, topic: &str) -> Result<()> {
+        self.client
+            .subscribe(topic, QoS::AtMostOnce)
+            .map_err(|e| Error::Mqtt(e.to_string()))
+    }
+
+    /// Publishes a payload on an arbitrary topic.
+    pub fn publish_topic(&mut self, topic: &str, payload: Vec<u8>) -> Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .map_err(|e| Error::Mqtt(e.to_string()))?;
+        self.pending_acks += 1;
+        Ok(())
+    }
+
+    /// Publishes a payload on a command topic of a device.
+    pub fn publish(&mut self, device_id: &str, command: &str, payload: Vec<u8>) -> Result<()> {
+        self.publish_topic(&command_topic(device_id, command), payload)
+    }
+
+    /// Sends a command to a device.
+    pub fn send(&mut self, device_id: &str, command: &Command) -> Result<()> {
+        self.publish(device_id, command.topic_suffix(), command.to_bytes())
+    }
+
+    /// Waits until the broker acknowledged every published command.
+    pub fn flush(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.pending_acks > 0 {
+            if self.poll(deadline)?.is_none() {
+                return Err(Error::Mqtt("timed out waiting for broker".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next message on a subscribed topic.
+    ///
+    /// Returns `None` if nothing was received before the timeout.
+    pub fn recv(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+        while self.received.is_empty() {
+            if self.poll(deadline)?.is_none() {
+                break;
+            }
+        }
+        Ok(self.received.pop_front())
+    }
+
+    pub fn disconnect(mut self) -> Result<()> {
+        self.client
+            .disconnect()
+            .map_err(|e| Error::Mqtt(e.to_string()))?;
+        /* Drive the connection until the disconnect is sent */
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while let Some(event) = self.poll(deadline)? {
+            if let MqttEvent::Outgoing(Outgoing::Disconnect) = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes the next connection event, keeping track of acknowledgements
+    /// and queuing incoming messages. Returns `None` once the deadline passed.
+    fn poll(&mut self, deadline: Instant) -> Result<Option<MqttEvent>> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        let event = match self.connection.recv_timeout(remaining) {
+            Ok(Ok(event)) => event,
+            Ok(Err(err)) => return Err(connection_error(err)),
+            Err(rumqttc::RecvTimeoutError::Timeout) => return Ok(None),
+            Err(rumqttc::RecvTimeoutError::Disconnected) => {
+                return Err(Error::Mqtt("connection closed".to_string()))
+            }
+        };
+        match &event {
+            MqttEvent::Incoming(Packet::PubAck(_)) => {
+                self.pending_acks = self.pending_acks.saturating_sub(1);
+            }
+            MqttEvent::Incoming(Packet::Publish(publish)) => {
+                self.received.push_back(Message {
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.to_vec(),
+                });
+            }
+            _ => (),
+        }
+        Ok(Some(event))
+    }
+}
+
+/// Blocking iterator over the typed messages of subscribed devices,
+/// created with [`MqttClient::updates`].
+///
+/// The iteration ends after a connection error.
+pub struct Updates<'a> {
+    client: &'a mut MqttClient,
+    states: HashMap<String, PlayerState>,
+    closed: bool,
+}
+
+impl MqttClient {
+    /// Iterates over the messages of subscribed devices, merging them into
+    /// the state of each device.
+    pub fn updates(&mut self) -> Updates<'_> {
+        Updates {
+            client: self,
+            states: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    /// Moves the client to a background thread and sends its updates on a
+    /// channel. The thread ends when the receiver is dropped or on a
+    /// connection error.
+    pub fn spawn(mut self) -> mpsc::Receiver<Result<DeviceUpdate>> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for update in self.updates() {
+                if sender.send(update).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+}
+
+impl Updates<'_> {
+    /// Current merged state of a device.
+    pub fn state(&self, device_id: &str) -> Option<&PlayerState> {
+        self.states.get(device_id)
+    }
+}
+
+impl Iterator for Updates<'_> {
+    type Item = Result<DeviceUpdate>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.closed {
+            let message = match self.client.recv(Duration::from_secs(60)) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(err) => {
+                    self.closed = true;
+                    return Some(Err(err));
+                }
+            };
+            match DeviceMessage::parse(&message) {
+                Ok(Some((device_id, message))) => {
+                    let state = self.states.entry(device_id.clone()).or_default();
+                    state.apply(&message);
+                    return Some(Ok(DeviceUpdate {
+                        device_id,
+                        message,
+                        state: state.clone(),
+                    }));
+                }
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    }
+}
+
+fn connection_error(err: ConnectionError) -> Error {
+    match err {
+        ConnectionError::ConnectionRefused(code) => {
+            Error::Mqtt(format!("connection refused by broker: {:?}", code))
+        }
+        err => Error::Mqtt(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_command(command: Command, topic: &str, payload: &str) {
+        assert_eq!(
+            command.topic("abc"),
+            format!("device/abc/command/{}", topic)
+        );
+        assert_eq!(String::from_utf8(command.to_bytes()).unwrap(), payload);
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_command(Command::Reboot, "reboot", "");
+        assert_command(Command::GetStatus, "status/request", "");
+        assert_command(Command::GetEvents, "events/request", "");
+        assert_command(Command::Stop, "card/stop", "");
+        assert_command(Command::Pause, "card/pause", "");
+        assert_command(Command::Resume, "card/resume", "");
+    }
+
+    #[test]
+    fn bluetooth_commands() {
+        assert_command(Command::BluetoothOn, "bluetooth/on", "");
+        assert_command(Command::BluetoothOff, "bluetooth/off", "");
+        assert_command(Command::BluetoothConnect, "bluetooth/connect", "");
+        assert_command(Command::BluetoothDisconnect, "bluetooth/disconnect", "");
+        assert_command(Command::BluetoothState, "bluetooth/state", "");
+    }
+
+    #[test]
+    fn settings_commands() {
+        assert_command(Command::SetVolume(8), "volume/set", r#"{"volume":8}"#);
+        assert_command(
+            Command::SetAmbient(255, 128, 0),
+            "ambients/set",
+            r#"{"b":0,"g":128,"r":255}"#,
+        );
+        assert_command(
+            Command::SetSleepTimer(600),
+            "sleep-timer/set",
+            r#"{"seconds":600}"#,
+        );
+        assert_command(
+            Command::ShowIcon {
+                uri: "/web/icon.png".to_string(),
+                timeout: 5,
+                animated: true,
+            },
+            "display/preview",
+            r#"{"animated":1,"timeout":5,"uri":"/web/icon.png"}"#,
+        );
+    }
+
+    #[test]
+    fn start_command() {
+        assert_command(
+            Command::Start(CardTarget::new("5WsQ9")),
+            "card/start",
+            r#"{"uri":"https://yoto.io/5WsQ9"}"#,
+        );
+        assert_command(
+            Command::Start(
+                CardTarget::new("5WsQ9")
+                    .chapter("02")
+                    .track("02-1")
+                    .seconds_in(83)
+                    .cut_off(120)
+                    .any_button_stop(true),
+            ),
+            "card/start",
+            r#"{"anyButtonStop":true,"chapterKey":"02","cutOff":120,"secondsIn":83,"trackKey":"02-1","uri":"https://yoto.io/5WsQ9"}"#,
+        );
+    }
+
+    #[test]
+    fn parse_commands() {
+        let commands = [
+            Command::Reboot,
+            Command::GetStatus,
+            Command::GetEvents,
+            Command::SetVolume(8),
+            Command::SetAmbient(255, 128, 0),
+            Command::SetSleepTimer(600),
+            Command::ShowIcon {
+                uri: "/web/icon.png".to_string(),
+                timeout: 5,
+                animated: false,
+            },
+            Command::Start(CardTarget::new("5WsQ9").chapter("02").seconds_in(83)),
+            Command::Stop,
+            Command::Pause,
+            Command::Resume,
+            Command::BluetoothOn,
+            Command::BluetoothOff,
+            Command::BluetoothConnect,
+            Command::BluetoothDisconnect,
+            Command::BluetoothState,
+        ];
+        for command in commands {
+            let parsed = Command::parse(command.topic_suffix(), &command.to_bytes()).unwrap();
+            assert_eq!(parsed, command);
+        }
+        assert!(Command::parse("volume/set", b"{}").is_err());
+        assert!(Command::parse("volume/set", br#"{"volume":-1}"#).is_err());
+        assert!(Command::parse("volume/set", br#"{"volume":4294967296}"#).is_err());
+        assert!(Command::parse("ambients/set", br#"{"r":300,"g":0,"b":0}"#).is_err());
+        assert!(Command::parse("unknown", b"").is_err());
+    }
+
+    fn message(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn merge_sparse_status() {
+        let mut state = PlayerState::default();
+        for payload in [
+            r#"{"status":{"batteryLevel":80,"volume":6,"activeCard":"5WsQ9"}}"#,
+            r#"{"volume":9}"#,
+        ] {
+            let (id, parsed) = DeviceMessage::parse(&message("device/abc/data/status", payload))
+                .unwrap()
+                .unwrap();
+            assert_eq!(id, "abc");
+            state.apply(&parsed);
+        }
+        assert_eq!(state.status.battery_level, Some(80));
+        assert_eq!(state.status.volume, Some(9));
+        assert_eq!(state.status.active_card.as_deref(), Some("5WsQ9"));
+    }
+
+    #[test]
+    fn parse_events_and_responses() {
+        let (_, parsed) = DeviceMessage::parse(&message(
+            "device/abc/data/events",
+            r#"{"playbackStatus":"playing","position":42,"trackKey":"01"}"#,
+        ))
+        .unwrap()
+        .unwrap();
+        match parsed {
+            DeviceMessage::Event(event) => {
+                assert_eq!(event.playback_status.as_deref(), Some("playing"));
+                assert_eq!(event.position, Some(42));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let (_, parsed) = DeviceMessage::parse(&message(
+            "device/abc/response",
+            r#"{"status":{"volume":"OK"}}"#,
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(matches!(parsed, DeviceMessage::Response(r) if r.status["volume"] == "OK"));
+
+        assert!(DeviceMessage::parse(&message("other/topic", "{}"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,204 @@
+//! Cards made from a folder of audio files.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::api::Client;
+use crate::error::{Error, Result};
+use crate::model::{Card, Chapter, MediaFormat};
+
+/// How to turn a folder into a card.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistOptions {
+    /// Title of the card, the folder name if not set.
+    pub title: Option<String>,
+    /// Put all the files as tracks of a single chapter, instead of one
+    /// chapter per file.
+    pub single_chapter: bool,
+    /// Take the titles from the file tags when available (requires the
+    /// `tags` feature), instead of the file names.
+    pub tag_titles: bool,
+}
+
+/// Compares strings the way humans order file names, with numbers compared
+/// by value: "2 - Intro" comes before "10 - Outro".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(c);
+                    }
+                    digits.trim_start_matches('0').to_string()
+                };
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let order = x.to_lowercase().cmp(y.to_lowercase());
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Lists the audio files of a folder, in natural order.
+pub fn audio_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let supported = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| MediaFormat::from_ext(ext).is_ok());
+        if path.is_file() && supported {
+            files.push(path);
+        }
+    }
+    files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(files)
+}
+
+/// Derives a title from a file name, without its extension and leading track
+/// number.
+pub fn file_title(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().replace('_', " "))
+        .unwrap_or_default();
+    let title = stem
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .trim_start_matches(|c: char| c.is_whitespace() || ".-)".contains(c));
+    if title.is_empty() || title.len() == stem.len() {
+        stem.trim().to_string()
+    } else {
+        title.trim().to_string()
+    }
+}
+
+#[cfg(feature = "tags")]
+fn tag_title(path: &Path) -> Option<String> {
+    use id3::TagLike;
+
+    let tag = id3::Tag::read_from_path(path).ok()?;
+    tag.title()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(not(feature = "tags"))]
+fn tag_title(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Uploads the audio files of a folder and builds a card playing them, to be
+/// submitted with [`Client::create_card`].
+///
+/// `on_upload` is called before each file is uploaded.
+pub fn card_from_folder<F>(
+    client: &Client,
+    dir: &Path,
+    options: &PlaylistOptions,
+    mut on_upload: F,
+) -> Result<Card>
+where
+    F: FnMut(&Path),
+{
+    let files = audio_files(dir)?;
+    if files.is_empty() {
+        return Err(Error::InvalidCard(format!(
+            "no audio files in {}",
+            dir.display()
+        )));
+    }
+
+    let title = match &options.title {
+        Some(title) => title.clone(),
+        None => dir
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    let mut card = Card::new(&title);
+    let mut chapter = Chapter::new("01", &title).overlay_label("1");
+    for (i, path) in files.iter().enumerate() {
+        let title = if options.tag_titles {
+            tag_title(path).unwrap_or_else(|| file_title(path))
+        } else {
+            file_title(path)
+        };
+        let key = format!("{:02}", i + 1);
+        let label = (i + 1).to_string();
+
+        on_upload(path);
+        let track = client
+            .upload_audio(path)?
+            .track(&key, &title)
+            .overlay_label(&label);
+        if options.single_chapter {
+            chapter = chapter.track(track);
+        } else {
+            card = card.chapter(
+                Chapter::new(&key, &title)
+                    .overlay_label(&label)
+                    .track(track),
+            );
+        }
+    }
+    if options.single_chapter {
+        card = card.chapter(chapter);
+    }
+    Ok(card)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "10 - End.mp3",
+            "2 - b.mp3",
+            "02 - A.mp3",
+            "1.mp3",
+            "Intro.mp3",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "1.mp3",
+                "02 - A.mp3",
+                "2 - b.mp3",
+                "10 - End.mp3",
+                "Intro.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn titles_from_file_names() {
+        assert_eq!(file_title(Path::new("a/01 - The Fox.mp3")), "The Fox");
+        assert_eq!(file_title(Path::new("a/3. Bed_time.ogg")), "Bed time");
+        assert_eq!(file_title(Path::new("a/1984.mp3")), "1984");
+        assert_eq!(file_title(Path::new("a/Chapter 2.mp3")), "Chapter 2");
+    }
+}
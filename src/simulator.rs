@@ -0,0 +1,580 @@
+//! Virtual player speaking the MQTT protocol of real players, for testing
+//! automations without hardware.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::model::{Card, DayMode};
+use crate::mqtt::*;
+
+/// Interval at which the simulated clock advances and playback events are
+/// published.
+pub const TICK: Duration = Duration::from_secs(1);
+
+/// Battery drain, in percent per second.
+const DRAIN_PLAYING: f64 = 1.0 / 360.0;
+const DRAIN_IDLE: f64 = 1.0 / 1800.0;
+
+/// Track length used for cards the simulator knows nothing about.
+const UNKNOWN_TRACK_LENGTH: u32 = 180;
+
+/// Message published by the simulator.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Status(Status),
+    Event(Event),
+    Response(Response),
+}
+
+#[derive(Clone, Debug)]
+struct TrackInfo {
+    chapter_key: String,
+    chapter_title: String,
+    key: String,
+    title: String,
+    length: u32,
+}
+
+#[derive(Clone, Debug)]
+struct Playback {
+    card_id: String,
+    tracks: Vec<TrackInfo>,
+    index: usize,
+    position: f64,
+    playing: bool,
+    cut_off: Option<u32>,
+}
+
+/// State machine of a simulated player.
+///
+/// Commands are fed with [`Simulator::handle`] and time advanced with
+/// [`Simulator::tick`], both returning the messages the player publishes.
+/// [`Simulator::run`] drives it from an MQTT connection.
+pub struct Simulator {
+    device_id: String,
+    cards: HashMap<String, Card>,
+    status: Status,
+    event: Event,
+    playback: Option<Playback>,
+    sleep_timer: Option<f64>,
+    battery: f64,
+}
+
+impl Output {
+    pub fn topic(&self, device_id: &str) -> String {
+        match self {
+            Output::Status(_) => status_topic(device_id),
+            Output::Event(_) => events_topic(device_id),
+            Output::Response(_) => response_topic(device_id),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let value = match self {
+            Output::Status(status) => json!({ "status": status }),
+            Output::Event(event) => json!(event),
+            Output::Response(response) => json!(response),
+        };
+        value.to_string().into_bytes()
+    }
+}
+
+impl Simulator {
+    pub fn new(device_id: &str) -> Simulator {
+        let status = Status {
+            status_version: Some(2),
+            fw_version: Some("simulator".to_string()),
+            product_type: Some("simulator".to_string()),
+            battery_level: Some(100),
+            charging: Some(false),
+            active_card: Some("none".to_string()),
+            card_inserted: Some(false),
+            playing_status: Some(PlayingStatus::Stopped),
+            headphones: Some(false),
+            bluetooth_hp: Some(false),
+            volume: Some(50),
+            user_volume: Some(50),
+            time_format: Some("24".to_string()),
+            nightlight_mode: Some("off".to_string()),
+            day: Some(DayMode::Day),
+            ..Status::default()
+        };
+        let event = Event {
+            volume: Some(50),
+            volume_max: Some(100),
+            sleep_timer_active: Some(false),
+            playback_status: Some("stopped".to_string()),
+            ..Event::default()
+        };
+        Simulator {
+            device_id: device_id.to_string(),
+            cards: HashMap::new(),
+            status,
+            event,
+            playback: None,
+            sleep_timer: None,
+            battery: 100.0,
+        }
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Makes a card known to the simulator, so that playing it follows its
+    /// chapters and track durations.
+    pub fn add_card(&mut self, card: Card) {
+        self.cards.insert(card.card_id.clone(), card);
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    pub fn set_charging(&mut self, charging: bool) {
+        self.status.charging = Some(charging);
+    }
+
+    /// Applies a command, returning the messages published in response.
+    pub fn handle(&mut self, command: &Command) -> Vec<Output> {
+        let mut outputs = vec![self.response(command, true)];
+        match command {
+            Command::Reboot => {
+                self.stop();
+                self.sleep_timer = None;
+                self.event.sleep_timer_active = Some(false);
+                self.event.sleep_timer_seconds = None;
+                outputs.push(self.status_output());
+                outputs.push(self.event_output());
+            }
+            Command::GetStatus => outputs = vec![self.status_output()],
+            Command::GetEvents => outputs = vec![self.event_output()],
+            Command::SetVolume(volume) => {
+                self.status.volume = Some(*volume);
+                self.status.user_volume = Some(*volume);
+                self.event.volume = Some(*volume);
+                outputs.push(self.status_output());
+                outputs.push(self.event_output());
+            }
+            Command::SetAmbient(r, g, b) => {
+                self.status.nightlight_mode = Some(if (*r, *g, *b) == (0, 0, 0) {
+                    "off".to_string()
+                } else {
+                    format!("0x{:02x}{:02x}{:02x}", r, g, b)
+                });
+                outputs.push(self.status_output());
+            }
+            Command::SetSleepTimer(seconds) => {
+                self.sleep_timer = match seconds {
+                    0 => None,
+                    s => Some(f64::from(*s)),
+                };
+                self.event.sleep_timer_active = Some(*seconds > 0);
+                self.event.sleep_timer_seconds = Some(*seconds);
+                outputs.push(self.event_output());
+            }
+            Command::ShowIcon { .. } => (),
+            Command::Start(target) => {
+                self.start(target);
+                outputs.push(self.status_output());
+                outputs.push(self.event_output());
+            }
+            Command::Stop => {
+                self.stop();
+                outputs.push(self.status_output());
+                outputs.push(self.event_output());
+            }
+            Command::Pause | Command::Resume => {
+                let playing = *command == Command::Resume;
+                match self.playback.as_mut() {
+                    Some(playback) => playback.playing = playing,
+                    None => return vec![self.response(command, false)],
+                }
+                self.update_playback();
+                outputs.push(self.status_output());
+                outputs.push(self.event_output());
+            }
+            Command::BluetoothOn | Command::BluetoothConnect => {
+                self.status.bluetooth_hp = Some(true);
+                outputs.push(self.status_output());
+            }
+            Command::BluetoothOff | Command::BluetoothDisconnect => {
+                self.status.bluetooth_hp = Some(false);
+                outputs.push(self.status_output());
+            }
+            Command::BluetoothState => (),
+        }
+        outputs
+    }
+
+    /// Advances the simulated clock, returning the messages published
+    /// meanwhile.
+    pub fn tick(&mut self, elapsed: Duration) -> Vec<Output> {
+        let seconds = elapsed.as_secs_f64();
+        let mut outputs = Vec::new();
+        let mut status_changed = self.drain_battery(seconds);
+
+        if let Some(remaining) = self.sleep_timer {
+            let remaining = remaining - seconds;
+            if remaining <= 0.0 {
+                self.sleep_timer = None;
+                self.event.sleep_timer_active = Some(false);
+                self.event.sleep_timer_seconds = Some(0);
+                if self.playback.is_some() {
+                    self.stop();
+                    status_changed = true;
+                }
+                outputs.push(self.event_output());
+            } else {
+                self.sleep_timer = Some(remaining);
+                self.event.sleep_timer_seconds = Some(remaining.ceil() as u32);
+            }
+        }
+
+        if let Some(playback) = self.playback.as_mut().filter(|p| p.playing) {
+            playback.position += seconds;
+            let mut finished = false;
+            loop {
+                let track = &playback.tracks[playback.index];
+                let stop_at = f64::from(match playback.cut_off {
+                    Some(cut_off) => cut_off.min(track.length),
+                    None => track.length,
+                });
+                if playback.position < stop_at {
+                    break;
+                }
+                if playback.cut_off.is_some() || playback.index + 1 == playback.tracks.len() {
+                    finished = true;
+                    break;
+                }
+                /* Carry the rest of the tick over to the next track */
+                playback.position -= stop_at;
+                playback.index += 1;
+            }
+            if finished {
+                self.stop();
+                status_changed = true;
+            } else {
+                self.update_playback();
+            }
+            outputs.push(self.event_output());
+        }
+
+        if status_changed {
+            outputs.insert(0, self.status_output());
+        }
+        outputs
+    }
+
+    /// Serves commands received on the device command topics until the
+    /// connection fails.
+    pub fn run(&mut self, mqtt: &mut MqttClient) -> Result<()> {
+        let prefix = command_topic(&self.device_id, "");
+        mqtt.subscribe_topic(&format!("{}#", prefix))?;
+        let initial = vec![self.status_output(), self.event_output()];
+        self.publish(mqtt, initial)?;
+
+        let mut last_tick = Instant::now();
+        loop {
+            let timeout = (last_tick + TICK).saturating_duration_since(Instant::now());
+            if let Some(message) = mqtt.recv(timeout)? {
+                if let Some(suffix) = message.topic.strip_prefix(&prefix) {
+                    let outputs = match Command::parse(suffix, &message.payload) {
+                        Ok(command) => self.handle(&command),
+                        Err(err) => vec![Output::Response(Response {
+                            status: [(suffix.to_string(), Value::from(err.to_string()))]
+                                .into_iter()
+                                .collect(),
+                        })],
+                    };
+                    self.publish(mqtt, outputs)?;
+                }
+            }
+
+            let now = Instant::now();
+            if now >= last_tick + TICK {
+                let outputs = self.tick(now - last_tick);
+                last_tick = now;
+                self.publish(mqtt, outputs)?;
+            }
+        }
+    }
+
+    fn publish(&self, mqtt: &mut MqttClient, outputs: Vec<Output>) -> Result<()> {
+        for output in outputs {
+            mqtt.publish_topic(&output.topic(&self.device_id), output.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn response(&self, command: &Command, ok: bool) -> Output {
+        let name = command.topic_suffix().split('/').next().unwrap_or_default();
+        let body = String::from_utf8(command.to_bytes()).unwrap_or_default();
+        Output::Response(Response {
+            status: [
+                (
+                    name.to_string(),
+                    Value::from(if ok { "OK" } else { "FAIL" }),
+                ),
+                ("req_body".to_string(), Value::from(body)),
+            ]
+            .into_iter()
+            .collect(),
+        })
+    }
+
+    fn status_output(&self) -> Output {
+        Output::Status(self.status.clone())
+    }
+
+    fn event_output(&self) -> Output {
+        Output::Event(Event {
+            event_utc: Some(chrono::Utc::now().timestamp()),
+            ..self.event.clone()
+        })
+    }
+
+    /// Returns whether the reported battery level changed.
+    fn drain_battery(&mut self, seconds: f64) -> bool {
+        let before = self.status.battery_level;
+        if self.status.charging == Some(true) {
+            self.battery = (self.battery + seconds * DRAIN_IDLE * 10.0).min(100.0);
+        } else {
+            let playing = self.playback.as_ref().is_some_and(|p| p.playing);
+            let rate = if playing { DRAIN_PLAYING } else { DRAIN_IDLE };
+            self.battery = (self.battery - seconds * rate).max(0.0);
+        }
+        self.status.battery_level = Some(self.battery.round() as u32);
+        self.status.battery_level != before
+    }
+
+    fn start(&mut self, target: &CardTarget) {
+        let card_id = target
+            .uri
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let tracks = match self.cards.get(&card_id) {
+            Some(card) => card_tracks(card),
+            None => Vec::new(),
+        };
+        let tracks = if tracks.is_empty() {
+            vec![TrackInfo {
+                chapter_key: "01".to_string(),
+                chapter_title: "Chapter 1".to_string(),
+                key: "01".to_string(),
+                title: "Track 1".to_string(),
+                length: UNKNOWN_TRACK_LENGTH,
+            }]
+        } else {
+            tracks
+        };
+
+        let index = tracks
+            .iter()
+            .position(|track| {
+                target
+                    .chapter_key
+                    .as_ref()
+                    .is_none_or(|key| *key == track.chapter_key)
+                    && target
+                        .track_key
+                        .as_ref()
+                        .is_none_or(|key| *key == track.key)
+            })
+            .unwrap_or(0);
+        self.playback = Some(Playback {
+            card_id,
+            tracks,
+            index,
+            position: f64::from(target.seconds_in.unwrap_or(0)),
+            playing: true,
+            cut_off: target.cut_off,
+        });
+        self.event.source = Some("MQTT".to_string());
+        self.update_playback();
+    }
+
+    fn stop(&mut self) {
+        self.playback = None;
+        self.update_playback();
+    }
+
+    /// Reflects the playback state in the status and event.
+    fn update_playback(&mut self) {
+        let Some(playback) = &self.playback else {
+            self.status.active_card = Some("none".to_string());
+            self.status.playing_status = Some(PlayingStatus::Stopped);
+            self.event.playback_status = Some("stopped".to_string());
+            self.event.position = Some(0);
+            return;
+        };
+        let track = &playback.tracks[playback.index];
+        self.status.active_card = Some(playback.card_id.clone());
+        self.status.playing_status = Some(if playback.playing {
+            PlayingStatus::Playing
+        } else {
+            PlayingStatus::Paused
+        });
+        self.event.card_id = Some(playback.card_id.clone());
+        self.event.chapter_key = Some(track.chapter_key.clone());
+        self.event.chapter_title = Some(track.chapter_title.clone());
+        self.event.track_key = Some(track.key.clone());
+        self.event.track_title = Some(track.title.clone());
+        self.event.track_length = Some(track.length);
+        self.event.position = Some(playback.position as u32);
+        self.event.playback_status = Some(
+            if playback.playing {
+                "playing"
+            } else {
+                "paused"
+            }
+            .to_string(),
+        );
+    }
+}
+
+fn card_tracks(card: &Card) -> Vec<TrackInfo> {
+    let mut tracks = Vec::new();
+    for chapter in card.content.chapters.iter() {
+        for track in chapter.tracks.iter() {
+            tracks.push(TrackInfo {
+                chapter_key: chapter.key.clone(),
+                chapter_title: chapter.title.clone(),
+                key: track.key.clone(),
+                title: track.title.clone(),
+                length: track.duration as u32,
+            });
+        }
+    }
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card() -> Card {
+        serde_json::from_value(json!({
+            "cardId": "5WsQ9",
+            "title": "Stories",
+            "content": {
+                "chapters": [
+                    {
+                        "key": "01",
+                        "title": "First",
+                        "tracks": [{
+                            "key": "01", "title": "One", "trackUrl": "yoto:#a",
+                            "type": "audio", "format": "mp3", "overlayLabel": "1",
+                            "duration": 10, "fileSize": 100
+                        }]
+                    },
+                    {
+                        "key": "02",
+                        "title": "Second",
+                        "tracks": [{
+                            "key": "02", "title": "Two", "trackUrl": "yoto:#b",
+                            "type": "audio", "format": "mp3", "overlayLabel": "2",
+                            "duration": 20, "fileSize": 100
+                        }]
+                    }
+                ]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn playback_advances_through_tracks() {
+        let mut simulator = Simulator::new("abc");
+        simulator.add_card(card());
+        simulator.handle(&Command::Start(CardTarget::new("5WsQ9")));
+        assert_eq!(simulator.event().track_key.as_deref(), Some("01"));
+
+        simulator.tick(Duration::from_secs(11));
+        assert_eq!(simulator.event().track_key.as_deref(), Some("02"));
+        assert_eq!(simulator.event().chapter_title.as_deref(), Some("Second"));
+
+        simulator.handle(&Command::Pause);
+        simulator.tick(Duration::from_secs(30));
+        assert_eq!(simulator.event().playback_status.as_deref(), Some("paused"));
+
+        simulator.handle(&Command::Resume);
+        simulator.tick(Duration::from_secs(30));
+        assert_eq!(
+            simulator.event().playback_status.as_deref(),
+            Some("stopped")
+        );
+        assert_eq!(simulator.status().active_card.as_deref(), Some("none"));
+    }
+
+    #[test]
+    fn tick_spans_several_tracks() {
+        let mut card = serde_json::to_value(card()).unwrap();
+        card["content"]["chapters"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({
+                "key": "03",
+                "title": "Third",
+                "tracks": [{
+                    "key": "03", "title": "Three", "trackUrl": "yoto:#c",
+                    "type": "audio", "format": "mp3", "overlayLabel": "3",
+                    "duration": 30, "fileSize": 100
+                }]
+            }));
+        let mut simulator = Simulator::new("abc");
+        simulator.add_card(serde_json::from_value(card).unwrap());
+        simulator.handle(&Command::Start(CardTarget::new("5WsQ9").seconds_in(5)));
+
+        /* 5 seconds left in the first track and 20 in the second */
+        simulator.tick(Duration::from_secs(32));
+        assert_eq!(simulator.event().track_key.as_deref(), Some("03"));
+        assert_eq!(simulator.event().position, Some(7));
+
+        simulator.tick(Duration::from_secs(60));
+        assert_eq!(
+            simulator.event().playback_status.as_deref(),
+            Some("stopped")
+        );
+    }
+
+    #[test]
+    fn sleep_timer_stops_playback() {
+        let mut simulator = Simulator::new("abc");
+        simulator.handle(&Command::Start(CardTarget::new("unknown").seconds_in(5)));
+        simulator.handle(&Command::SetSleepTimer(60));
+        assert_eq!(simulator.event().position, Some(5));
+
+        simulator.tick(Duration::from_secs(61));
+        assert_eq!(simulator.event().sleep_timer_active, Some(false));
+        assert_eq!(
+            simulator.event().playback_status.as_deref(),
+            Some("stopped")
+        );
+    }
+
+    #[test]
+    fn settings_update_status() {
+        let mut simulator = Simulator::new("abc");
+        let outputs = simulator.handle(&Command::SetVolume(30));
+        assert!(matches!(&outputs[0], Output::Response(r) if r.status["volume"] == "OK"));
+        assert_eq!(simulator.status().volume, Some(30));
+
+        simulator.handle(&Command::SetAmbient(255, 0, 16));
+        assert_eq!(
+            simulator.status().nightlight_mode.as_deref(),
+            Some("0xff0010")
+        );
+
+        simulator.tick(Duration::from_secs(3600));
+        assert_eq!(simulator.status().battery_level, Some(98));
+    }
+}
@@ -0,0 +1,275 @@
+//! Persistent storage of the OAuth token.
+
+#[cfg(feature = "keyring")]
+use keyring::Entry;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::api::Token;
+#[cfg(feature = "keyring")]
+use crate::error::Error;
+use crate::error::Result;
+
+pub static KEYRING_SERVICE: &str = "yoto-api";
+pub static KEYRING_USER: &str = "oauth";
+pub static TOKEN_ENV: &str = "YOTO_TOKEN";
+
+/// Persistent storage for the OAuth token.
+pub trait TokenStore: Send + Sync {
+    fn load(&self) -> Result<Option<Token>>;
+    fn save(&self, token: &Token) -> Result<()>;
+    fn clear(&self) -> Result<()>;
+}
+
+/// Stores the token in the OS keyring.
+#[cfg(feature = "keyring")]
+pub struct KeyringStore {
+    entry: Entry,
+}
+
+/// Stores the token as JSON in a file only readable by the current user.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+/// Reads the token as JSON from an environment variable.
+///
+/// The environment is never modified: refreshed tokens are only kept in
+/// memory for the lifetime of the store.
+pub struct EnvStore {
+    var: String,
+    token: Mutex<Option<Option<Token>>>,
+}
+
+/// Keeps the token in memory only.
+#[derive(Default)]
+pub struct MemoryStore {
+    token: Mutex<Option<Token>>,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringStore {
+    pub fn new() -> Result<KeyringStore> {
+        KeyringStore::with_entry(KEYRING_SERVICE, KEYRING_USER)
+    }
+
+    pub fn with_entry(service: &str, user: &str) -> Result<KeyringStore> {
+        let entry = Entry::new(service, user).map_err(|e| Error::Store(e.to_string()))?;
+        Ok(KeyringStore { entry })
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl TokenStore for KeyringStore {
+    fn load(&self) -> Result<Option<Token>> {
+        match self.entry.get_password() {
+            Ok(password) => Ok(Some(serde_json::from_str(&password)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(Error::Store(err.to_string())),
+        }
+    }
+
+    fn save(&self, token: &Token) -> Result<()> {
+        self.entry
+            .set_password(&serde_json::to_string(token)?)
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn clear(&self) -> Result<()> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(Error::Store(err.to_string())),
+        }
+    }
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileStore {
+        FileStore { path: path.into() }
+    }
+
+    /// Default location of the token file, in the user configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("yoto").join("token.json"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl TokenStore for FileStore {
+    fn load(&self) -> Result<Option<Token>> {
+        match fs::read_to_string(&self.path) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, token: &Token) -> Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+
+        /* Temporary files are created with 0600 permissions */
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(serde_json::to_string_pretty(token)?.as_bytes())?;
+        file.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl EnvStore {
+    pub fn new() -> EnvStore {
+        EnvStore::with_var(TOKEN_ENV)
+    }
+
+    pub fn with_var(var: &str) -> EnvStore {
+        EnvStore {
+            var: var.to_string(),
+            token: Mutex::new(None),
+        }
+    }
+}
+
+impl Default for EnvStore {
+    fn default() -> EnvStore {
+        EnvStore::new()
+    }
+}
+
+impl TokenStore for EnvStore {
+    fn load(&self) -> Result<Option<Token>> {
+        if let Some(token) = self.token.lock().unwrap().as_ref() {
+            return Ok(token.clone());
+        }
+        match env::var(&self.var) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn save(&self, token: &Token) -> Result<()> {
+        *self.token.lock().unwrap() = Some(Some(token.clone()));
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.token.lock().unwrap() = Some(None);
+        Ok(())
+    }
+}
+
+impl MemoryStore {
+    pub fn new(token: Option<Token>) -> MemoryStore {
+        MemoryStore {
+            token: Mutex::new(token),
+        }
+    }
+}
+
+impl TokenStore for MemoryStore {
+    fn load(&self) -> Result<Option<Token>> {
+        Ok(self.token.lock().unwrap().clone())
+    }
+
+    fn save(&self, token: &Token) -> Result<()> {
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.token.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn token(access_token: &str) -> Token {
+        serde_json::from_value(json!({
+            "access_token": access_token,
+            "refresh_token": "refresh",
+            "valid_until": "2999-01-01T00:00:00Z"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("yoto").join("token.json");
+        let store = FileStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+
+        store.save(&token("one")).unwrap();
+        store.save(&token("two")).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().access_token(), "two");
+        /* Replaced atomically, without leaving temporary files behind */
+        let files: Vec<_> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["token.json"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        store.clear().unwrap();
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn env_store() {
+        let var = "YOTO_TEST_ENV_STORE";
+        let store = EnvStore::with_var(var);
+        env::remove_var(var);
+        assert!(store.load().unwrap().is_none());
+
+        let data = serde_json::to_string(&token("env")).unwrap();
+        env::set_var(var, &data);
+        assert_eq!(store.load().unwrap().unwrap().access_token(), "env");
+
+        /* Saved tokens shadow the variable, which is left untouched */
+        store.save(&token("saved")).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().access_token(), "saved");
+        assert_eq!(env::var(var).unwrap(), data);
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+        assert_eq!(env::var(var).unwrap(), data);
+
+        env::set_var(var, "not json");
+        assert!(EnvStore::with_var(var).load().is_err());
+        env::remove_var(var);
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::default();
+        assert!(store.load().unwrap().is_none());
+        store.save(&token("memory")).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().access_token(), "memory");
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_none());
+    }
+}
@@ -0,0 +1,137 @@
+//! Helpers shared by the tests of several modules.
+
+use chrono::{TimeDelta, Utc};
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::api::{Client, ClientBuilder, Token};
+use crate::error::{Error, Result};
+use crate::store::{MemoryStore, TokenStore};
+
+/// Serves HTTP requests on a local port with `handler`, which gets the
+/// method, path and body of each request and returns the response body.
+pub fn serve<F>(handler: F) -> String
+where
+    F: Fn(&str, &str, &[u8]) -> Vec<u8> + Send + 'static,
+{
+    serve_with_status(move |method, path, body| (200, handler(method, path, body)))
+}
+
+/// Like [`serve`], with `handler` also returning the response status.
+pub fn serve_with_status<F>(handler: F) -> String
+where
+    F: Fn(&str, &str, &[u8]) -> (u16, Vec<u8>) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut request = line.split_whitespace();
+            let (method, path) = (request.next().unwrap(), request.next().unwrap());
+
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, response) = handler(method, path, &body);
+            write!(
+                stream,
+                "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                response.len()
+            )
+            .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+    url
+}
+
+/// Serves a token endpoint and a card list, which rejects the first
+/// `rejections` requests. Returns the URL and the requests received.
+pub fn token_server(rejections: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let url = {
+        let requests = requests.clone();
+        serve_with_status(move |method, path, body| {
+            let mut requests = requests.lock().unwrap();
+            requests.push(format!("{} {}", method, path));
+            match path {
+                "/oauth/token" => {
+                    assert!(String::from_utf8_lossy(body).contains("refresh_token=refresh"));
+                    let token = json!({ "access_token": "new", "expires_in": 3600 });
+                    (200, token.to_string().into_bytes())
+                }
+                _ if requests.len() <= rejections => (401, b"{}".to_vec()),
+                _ => (200, json!({ "cards": [] }).to_string().into_bytes()),
+            }
+        })
+    };
+    (url, requests)
+}
+
+/// A token refreshable on [`token_server`], expiring after `valid_for`.
+pub fn token(valid_for: TimeDelta) -> Token {
+    serde_json::from_value(json!({
+        "access_token": "old",
+        "refresh_token": "refresh",
+        "valid_until": Utc::now() + valid_for
+    }))
+    .unwrap()
+}
+
+pub fn token_client(url: &str, token: Token) -> ClientBuilder {
+    Client::builder("id")
+        .base_url(url)
+        .token_url(format!("{}/oauth/token", url))
+        .token(Some(token))
+}
+
+/// A token store failing to save tokens.
+pub struct ReadOnlyStore;
+
+impl TokenStore for ReadOnlyStore {
+    fn load(&self) -> Result<Option<Token>> {
+        Ok(None)
+    }
+
+    fn save(&self, _: &Token) -> Result<()> {
+        Err(Error::Store("read-only".to_string()))
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn client(url: &str) -> Client {
+    let token: Token = serde_json::from_value(json!({
+        "access_token": "token",
+        "valid_until": "2999-01-01T00:00:00Z"
+    }))
+    .unwrap();
+    Client::builder("test")
+        .base_url(url)
+        .token_store(Arc::new(MemoryStore::new(Some(token))))
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,187 @@
+//! Changes of the status of a player, found by polling the REST API.
+//!
+//! This is a coarser alternative to the MQTT messages of players, which
+//! does not need a connection to the broker.
+
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::api::Client;
+use crate::error::Result;
+use crate::model::{DayMode, DeviceStatus, PowerSource};
+
+/// Change between two statuses of a player.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Online(bool),
+    Charging(bool),
+    PowerSource(PowerSource),
+    Battery {
+        from: u32,
+        to: u32,
+    },
+    /// Card now being played, `None` once removed.
+    Card(Option<String>),
+    Volume {
+        from: u32,
+        to: u32,
+    },
+    DayMode(DayMode),
+}
+
+fn active_card(status: &DeviceStatus) -> Option<&str> {
+    match status.active_card() {
+        "" | "none" => None,
+        card => Some(card),
+    }
+}
+
+/// Lists the changes from `old` to `new`.
+pub fn changes(old: &DeviceStatus, new: &DeviceStatus) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if old.online() != new.online() {
+        changes.push(Change::Online(new.online()));
+    }
+    if active_card(old) != active_card(new) {
+        changes.push(Change::Card(active_card(new).map(str::to_string)));
+    }
+    if old.charging() != new.charging() {
+        changes.push(Change::Charging(new.charging()));
+    }
+    if old.power_source() != new.power_source() {
+        changes.push(Change::PowerSource(new.power_source()));
+    }
+    if old.battery_level() != new.battery_level() {
+        changes.push(Change::Battery {
+            from: old.battery_level(),
+            to: new.battery_level(),
+        });
+    }
+    if old.user_volume() != new.user_volume() {
+        changes.push(Change::Volume {
+            from: old.user_volume(),
+            to: new.user_volume(),
+        });
+    }
+    if old.day_mode() != new.day_mode() {
+        changes.push(Change::DayMode(new.day_mode()));
+    }
+    changes
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Online(true) => write!(f, "went online"),
+            Change::Online(false) => write!(f, "went offline"),
+            Change::Charging(true) => write!(f, "started charging"),
+            Change::Charging(false) => write!(f, "stopped charging"),
+            Change::PowerSource(source) => write!(f, "powered by {}", source),
+            Change::Battery { from, to } => write!(f, "battery {}% -> {}%", from, to),
+            Change::Card(Some(card)) => write!(f, "card {} inserted", card),
+            Change::Card(None) => write!(f, "card removed"),
+            Change::Volume { from, to } => write!(f, "volume {}% -> {}%", from, to),
+            Change::DayMode(DayMode::Day) => write!(f, "switched to day mode"),
+            Change::DayMode(DayMode::Night) => write!(f, "switched to night mode"),
+            Change::DayMode(DayMode::Unknown(code)) => write!(f, "day mode {}", code),
+        }
+    }
+}
+
+/// Polls the status of a player and reports what changed between polls.
+pub struct StatusWatcher<'a> {
+    client: &'a Client,
+    device_id: String,
+    interval: Duration,
+    last: Option<DeviceStatus>,
+}
+
+impl<'a> StatusWatcher<'a> {
+    pub fn new(client: &'a Client, device_id: &str, interval: Duration) -> StatusWatcher<'a> {
+        StatusWatcher {
+            client,
+            device_id: device_id.to_string(),
+            interval,
+            last: None,
+        }
+    }
+
+    /// Last status retrieved.
+    pub fn status(&self) -> Option<&DeviceStatus> {
+        self.last.as_ref()
+    }
+
+    /// Retrieves the status and returns its changes since the previous poll,
+    /// none on the first one.
+    pub fn poll(&mut self) -> Result<Vec<Change>> {
+        let status = self.client.get_device_status(&self.device_id)?;
+        let changes = match &self.last {
+            Some(last) => changes(last, &status),
+            None => Vec::new(),
+        };
+        self.last = Some(status);
+        Ok(changes)
+    }
+
+    /// Polls at the watcher interval, calling `on_event` for every change,
+    /// and for every failed poll, until it returns `false`. Failed polls do
+    /// not stop the watch: the next one is attempted after the interval.
+    pub fn run<F>(&mut self, mut on_event: F)
+    where
+        F: FnMut(Result<Change>) -> bool,
+    {
+        loop {
+            let events = match self.poll() {
+                Ok(changes) => changes.into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            };
+            for event in events {
+                if !on_event(event) {
+                    return;
+                }
+            }
+            sleep(self.interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn status(changes: Value) -> DeviceStatus {
+        let mut status: Value =
+            serde_json::from_str(include_str!("../tests/data/device_status.json")).unwrap();
+        for (key, value) in changes.as_object().unwrap() {
+            status[key] = value.clone();
+        }
+        serde_json::from_value(status).unwrap()
+    }
+
+    #[test]
+    fn status_changes() {
+        let old = status(json!({}));
+        assert_eq!(changes(&old, &old), vec![]);
+
+        let new = status(json!({
+            "isOnline": false,
+            "activeCard": "5WsQ9",
+            "isCharging": false,
+            "powerSource": 0,
+            "batteryLevelPercentage": 85,
+        }));
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                Change::Online(false),
+                Change::Card(Some("5WsQ9".to_string())),
+                Change::Charging(false),
+                Change::PowerSource(PowerSource::Battery),
+                Change::Battery { from: 87, to: 85 },
+            ]
+        );
+        assert_eq!(changes(&new, &old)[1], Change::Card(None));
+    }
+}